@@ -0,0 +1,60 @@
+use soroban_sdk::{Address, Env};
+
+use crate::load_config;
+use crate::types::{DataKey, Error, Role};
+
+/// Checks whether an account holds a role
+///
+/// The admin implicitly holds every role except `Role::Arbiter`, so that
+/// dispute outcomes are decided by the appointed panel alone. An admin who
+/// should vote must be granted the role like any other account.
+///
+/// # Arguments
+/// * `env` - The Soroban environment
+/// * `role` - The role to check
+/// * `account` - The account to check
+pub fn has_role(env: &Env, role: Role, account: &Address) -> Result<bool, Error> {
+    let config = load_config(env)?;
+    if *account == config.admin && role != Role::Arbiter {
+        return Ok(true);
+    }
+    if role == Role::Admin {
+        return Ok(false);
+    }
+
+    Ok(env
+        .storage()
+        .persistent()
+        .get(&DataKey::Role(role, account.clone()))
+        .unwrap_or(false))
+}
+
+/// Requires `caller` to authorize and to hold `role`
+///
+/// # Returns
+/// `Error::UnauthorizedAccess` if the caller lacks the role
+pub fn require_role(env: &Env, caller: &Address, role: Role) -> Result<(), Error> {
+    caller.require_auth();
+    if !has_role(env, role, caller)? {
+        return Err(Error::UnauthorizedAccess);
+    }
+    Ok(())
+}
+
+/// Records or removes a role membership
+///
+/// The admin role is not stored here; it moves via `propose_admin` and
+/// `accept_admin`.
+pub fn set_role(env: &Env, role: Role, account: &Address, granted: bool) -> Result<(), Error> {
+    if role == Role::Admin {
+        return Err(Error::InvalidRole);
+    }
+
+    let key = DataKey::Role(role, account.clone());
+    if granted {
+        env.storage().persistent().set(&key, &true);
+    } else {
+        env.storage().persistent().remove(&key);
+    }
+    Ok(())
+}
@@ -0,0 +1,38 @@
+use soroban_sdk::{BytesN, Env};
+
+use crate::types::{ArbitrationConfig, DataKey, Dispute, Error};
+
+/// Reads the dispute settings (the defaults until the admin sets them)
+pub fn arbitration_config(env: &Env) -> ArbitrationConfig {
+    env.storage()
+        .instance()
+        .get(&DataKey::ArbitrationConfig)
+        .unwrap_or_else(ArbitrationConfig::defaults)
+}
+
+/// Loads the dispute of an escrow invoice
+///
+/// # Returns
+/// `Error::DisputeNotFound` if the invoice was never disputed
+pub fn load_dispute(env: &Env, invoice_id: &BytesN<32>) -> Result<Dispute, Error> {
+    env.storage()
+        .persistent()
+        .get(&DataKey::Dispute(invoice_id.clone()))
+        .ok_or(Error::DisputeNotFound)
+}
+
+/// Writes a dispute back to storage
+pub fn save_dispute(env: &Env, dispute: &Dispute) {
+    env.storage()
+        .persistent()
+        .set(&DataKey::Dispute(dispute.invoice_id.clone()), dispute);
+}
+
+/// Counts the votes cast for returning `payer_amount` to the payer
+pub fn votes_for(dispute: &Dispute, payer_amount: i128) -> u32 {
+    dispute
+        .votes
+        .iter()
+        .filter(|vote| vote.payer_amount == payer_amount)
+        .count() as u32
+}
@@ -0,0 +1,158 @@
+use soroban_sdk::{Address, Env, Vec};
+
+use crate::fee_config;
+use crate::pricing;
+use crate::types::{
+    DataKey, Error, FeeSource, FeeTier, VolumeBucket, BPS_DENOMINATOR, MAX_FEE_TIERS,
+    VOLUME_BUCKET_SECS, VOLUME_WINDOW_BUCKETS,
+};
+
+/// Checks a fee tier table
+///
+/// At most `MAX_FEE_TIERS` tiers, strictly ascending by a non-negative
+/// `min_volume`, each with a rate of at most 100%.
+pub fn validate_tiers(tiers: &Vec<FeeTier>) -> Result<(), Error> {
+    if tiers.len() > MAX_FEE_TIERS {
+        return Err(Error::InvalidFeeTier);
+    }
+
+    let mut previous: Option<i128> = None;
+    for tier in tiers.iter() {
+        if tier.min_volume < 0 || tier.bps as i128 > BPS_DENOMINATOR {
+            return Err(Error::InvalidFeeTier);
+        }
+        if previous.is_some_and(|min_volume| tier.min_volume <= min_volume) {
+            return Err(Error::InvalidFeeTier);
+        }
+        previous = Some(tier.min_volume);
+    }
+    Ok(())
+}
+
+/// Reads the fee tier table of a token (empty if none is set)
+pub fn fee_tiers(env: &Env, token: &Address) -> Vec<FeeTier> {
+    env.storage()
+        .persistent()
+        .get(&DataKey::FeeTiers(token.clone()))
+        .unwrap_or_else(|| Vec::new(env))
+}
+
+/// Reads the minimum fee of a token (zero if none is set)
+pub fn min_fee(env: &Env, token: &Address) -> i128 {
+    env.storage()
+        .persistent()
+        .get(&DataKey::MinFee(token.clone()))
+        .unwrap_or(0)
+}
+
+/// Reads a merchant's fee rate override
+pub fn merchant_override(env: &Env, merchant: &Address) -> Option<u32> {
+    env.storage()
+        .persistent()
+        .get(&DataKey::MerchantFeeOverride(merchant.clone()))
+}
+
+/// Computes the platform fee on a payment to `merchant`
+///
+/// The rate is the merchant's override if one is set, otherwise the highest
+/// of `token`'s tiers reached by the merchant's rolling volume in `token`
+/// before this payment, otherwise `FeeConfig::bps`. The flat minimum is
+/// `token`'s (see `min_fee`) and the collector always comes from the fee
+/// config. The minimum is charged once per invoice: a payment covering
+/// `paid..paid + amount` of a `total` owes that part of it, so installments
+/// add up to exactly one minimum.
+///
+/// # Returns
+/// The fee, the address it is owed to, and which rate was applied
+pub fn platform_fee(
+    env: &Env,
+    merchant: &Address,
+    token: &Address,
+    amount: i128,
+    paid: i128,
+    total: i128,
+) -> Result<(i128, Option<Address>, FeeSource), Error> {
+    let Some(config) = fee_config(env) else {
+        return Ok((0, None, FeeSource::NoFee));
+    };
+
+    let (bps, source) = match merchant_override(env, merchant) {
+        Some(bps) => (bps, FeeSource::Override),
+        None => {
+            let volume = rolling_volume(env, merchant, token);
+            let mut rate = (config.bps, FeeSource::Base);
+            for (index, tier) in fee_tiers(env, token).iter().enumerate() {
+                if volume >= tier.min_volume {
+                    rate = (tier.bps, FeeSource::Tier(index as u32));
+                }
+            }
+            rate
+        }
+    };
+
+    let token_min_fee = min_fee(env, token);
+    let min_fee = pricing::prorate_fee(token_min_fee, paid + amount, total)?
+        - pricing::prorate_fee(token_min_fee, paid, total)?;
+    let fee = pricing::compute_fee(bps, min_fee, amount)?;
+    Ok((fee, Some(config.collector), source))
+}
+
+/// Sums a merchant's settled volume in `token` over the last
+/// `VOLUME_WINDOW_BUCKETS` days, including today
+pub fn rolling_volume(env: &Env, merchant: &Address, token: &Address) -> i128 {
+    let today = env.ledger().timestamp() / VOLUME_BUCKET_SECS;
+    volume_buckets(env, merchant, token)
+        .iter()
+        .filter(|bucket| bucket.day + VOLUME_WINDOW_BUCKETS > today)
+        .fold(0i128, |total, bucket| total.saturating_add(bucket.volume))
+}
+
+/// Adds a settled amount to today's bucket, dropping buckets that have
+/// left the rolling window
+pub fn record_volume(env: &Env, merchant: &Address, token: &Address, amount: i128) {
+    let today = env.ledger().timestamp() / VOLUME_BUCKET_SECS;
+    let mut buckets = Vec::new(env);
+    let mut recorded = false;
+    for mut bucket in volume_buckets(env, merchant, token).iter() {
+        if bucket.day + VOLUME_WINDOW_BUCKETS <= today {
+            continue;
+        }
+        if bucket.day == today {
+            bucket.volume = bucket.volume.saturating_add(amount);
+            recorded = true;
+        }
+        buckets.push_back(bucket);
+    }
+    if !recorded {
+        buckets.push_back(VolumeBucket { day: today, volume: amount });
+    }
+
+    env.storage()
+        .persistent()
+        .set(&DataKey::MerchantVolume(merchant.clone(), token.clone()), &buckets);
+}
+
+/// Takes a refunded amount back out of the bucket of the day it settled,
+/// if that bucket is still inside the rolling window
+pub fn deduct_volume(env: &Env, merchant: &Address, token: &Address, amount: i128, settled_at: u64) {
+    let day = settled_at / VOLUME_BUCKET_SECS;
+    let mut buckets = volume_buckets(env, merchant, token);
+    let Some(index) = buckets.iter().position(|bucket| bucket.day == day) else {
+        return;
+    };
+
+    let mut bucket = buckets.get_unchecked(index as u32);
+    bucket.volume = (bucket.volume - amount).max(0);
+    buckets.set(index as u32, bucket);
+    env.storage()
+        .persistent()
+        .set(&DataKey::MerchantVolume(merchant.clone(), token.clone()), &buckets);
+}
+
+/// Reads the stored buckets, which may include some outside the window
+fn volume_buckets(env: &Env, merchant: &Address, token: &Address) -> Vec<VolumeBucket> {
+    env.storage()
+        .persistent()
+        .get(&DataKey::MerchantVolume(merchant.clone(), token.clone()))
+        .unwrap_or_else(|| Vec::new(env))
+}
@@ -1,298 +1,2884 @@
 #![no_std]
 
+mod access;
+mod disputes;
+mod fees;
+mod migration;
+mod pricing;
 mod types;
 
-use soroban_sdk::{contract, contractimpl, symbol_short, token, Address, Env, BytesN};
-use types::{Invoice, Payment, InvoiceStatus, DataKey, generate_invoice_id, Error};
+use soroban_sdk::{contract, contractimpl, symbol_short, token, Address, Bytes, Env, BytesN, Map, Vec};
+use types::{
+    AmountMode, ArbitrationConfig, CheckoutPolicy, Config, Discount, Dispute, DisputeStatus, DisputeVote, Escrow, FeeConfig, FeeRefund, FeeTier, Invoice, InvoiceBreakdown, InvoiceMetadata, InvoiceOptions, InvoiceSpec, LineItem, Payment, PaymentLink, Refund, RefundRequest, RefundRequestStatus, InvoiceStatus, DataKey, Operation, Role, derive_invoice_id, generate_cart_id, generate_invoice_id,
+    Error, BPS_DENOMINATOR, MAX_BATCH_SIZE, MAX_EXTERNAL_REF_LEN,
+    MAX_REFUND_WINDOW_SECS, MAX_RELEASE_DELAY_SECS, SAC_RECIPIENT_ERRORS, SCHEMA_VERSION,
+    DEFAULT_MIN_AMOUNT, DEFAULT_MAX_AMOUNT, DEFAULT_MIN_EXPIRY_SECS, DEFAULT_MAX_EXPIRY_SECS,
+    DEFAULT_REFUND_WINDOW_SECS, TX_WRITE_ENTRIES,
+};
 
 #[contract]
 pub struct CheckoutContract;
 
 #[contractimpl]
 impl CheckoutContract {
-    /// Initialize the contract with the USDC token address
+    /// Initialize the contract with its admin and the USDC token address
+    /// 
+    /// Can only be called once; the stored `Config` starts with the
+    /// default amount and expiry limits as its default policy.
     /// 
     /// # Arguments
+    /// * `admin` - Administrator address (must authorize this call)
     /// * `usdc_address` - Address of the USDC token contract
-    pub fn initialize(env: Env, usdc_address: Address) {
+    pub fn initialize(env: Env, admin: Address, usdc_address: Address) -> Result<(), Error> {
+        if env.storage().instance().has(&DataKey::Config) {
+            return Err(Error::AlreadyInitialized);
+        }
+        
+        admin.require_auth();
+        
+        let config = Config {
+            admin: admin.clone(),
+            token: usdc_address.clone(),
+            default_policy: CheckoutPolicy {
+                min_amount: DEFAULT_MIN_AMOUNT,
+                max_amount: DEFAULT_MAX_AMOUNT,
+                min_expiry_secs: DEFAULT_MIN_EXPIRY_SECS,
+                max_expiry_secs: DEFAULT_MAX_EXPIRY_SECS,
+            },
+        };
+        env.storage().instance().set(&DataKey::Config, &config);
+        env.storage().instance().set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
         env.storage()
-            .instance()
-            .set(&symbol_short!("USDC"), &usdc_address);
+            .persistent()
+            .set(&DataKey::AllowedToken(usdc_address.clone()), &true);
+        
+        env.events().publish(
+            (symbol_short!("init"), admin),
+            usdc_address,
+        );
+        
+        Ok(())
     }
     
-    /// Creates a new invoice for payment
-    /// 
-    /// # Arguments
-    /// * `merchant` - The merchant's address (must authorize this call)
-    /// * `amount` - Amount in USDC stroops (7 decimals)
-    /// * `expiry` - Unix timestamp when invoice expires
+    /// Get the contract configuration
     /// 
     /// # Returns
-    /// * Invoice ID (BytesN<32>)
-    pub fn create_invoice(
-        env: Env,
-        merchant: Address,
-        amount: i128,
-        expiry: u64,
-    ) -> Result<BytesN<32>, Error> {
-        // 1. Require merchant authorization
-        merchant.require_auth();
-        
-        // 2. Validate amount
-        if amount <= 0 {
-            return Err(Error::InvalidAmount);
-        }
-        
-        // 3. Validate expiry (must be 5-60 minutes in future)
-        let current_time = env.ledger().timestamp();
-        let min_expiry = current_time + 300;  // 5 minutes
-        let max_expiry = current_time + 3600; // 60 minutes
+    /// * Config - Current configuration, or `Error::NotInitialized`
+    pub fn get_config(env: Env) -> Result<Config, Error> {
+        load_config(&env)
+    }
+    
+    /// Propose a new admin (first step of the handover)
+    /// 
+    /// The current admin stays in charge until `new_admin` calls
+    /// `accept_admin`, so a mistyped address can simply be replaced.
+    /// 
+    /// # Arguments
+    /// * `new_admin` - Proposed admin address (current admin must authorize)
+    pub fn propose_admin(env: Env, new_admin: Address) -> Result<(), Error> {
+        let config = load_config(&env)?;
+        config.admin.require_auth();
         
-        if expiry < min_expiry || expiry > max_expiry {
-            return Err(Error::InvalidExpiry);
-        }
+        env.storage().instance().set(&DataKey::PendingAdmin, &new_admin);
         
-        // 4. Generate unique invoice ID
-        let invoice_id = generate_invoice_id(&env, &merchant);
+        env.events().publish(
+            (symbol_short!("adm_prop"), config.admin),
+            new_admin,
+        );
         
-        // 5. Create invoice
-        let invoice = Invoice {
-            id: invoice_id.clone(),
-            merchant: merchant.clone(),
-            amount,
-            expiry,
-            status: InvoiceStatus::Open,
-            created_at: current_time,
-            payer: None,
-        };
+        Ok(())
+    }
+    
+    /// Accept a pending admin proposal (second step of the handover)
+    /// 
+    /// Must be authorized by the proposed admin.
+    pub fn accept_admin(env: Env) -> Result<(), Error> {
+        let mut config = load_config(&env)?;
+        let new_admin: Address = env
+            .storage()
+            .instance()
+            .get(&DataKey::PendingAdmin)
+            .ok_or(Error::NoPendingAdmin)?;
+        new_admin.require_auth();
         
-        // 6. Store in persistent storage
-        env.storage()
-            .persistent()
-            .set(&DataKey::Invoice(invoice_id.clone()), &invoice);
+        let old_admin = config.admin;
+        config.admin = new_admin.clone();
+        env.storage().instance().set(&DataKey::Config, &config);
+        env.storage().instance().remove(&DataKey::PendingAdmin);
         
-        // 7. Emit event
         env.events().publish(
-            (symbol_short!("created"), merchant.clone()),
-            (invoice_id.clone(), amount, expiry),
+            (symbol_short!("adm_xfer"), new_admin),
+            old_admin,
         );
         
-        Ok(invoice_id)
+        Ok(())
     }
     
-    /// Pay an invoice with USDC
-    /// 
-    /// # Arguments
-    /// * `invoice_id` - The invoice to pay
-    /// * `payer` - The payer's address (must authorize)
-    /// * `amount` - Amount in USDC stroops (must match invoice exactly)
+    /// Get the admin proposed by `propose_admin`, if any
     /// 
     /// # Returns
-    /// * Ok(()) on success
-    pub fn pay(
-        env: Env,
-        invoice_id: BytesN<32>,
-        payer: Address,
-        amount: i128,
-    ) -> Result<(), Error> {
-        // 1. Require payer authorization
-        payer.require_auth();
-        
-        // 2. Load invoice
-        let mut invoice: Invoice = env
-            .storage()
-            .persistent()
-            .get(&DataKey::Invoice(invoice_id.clone()))
-            .ok_or(Error::InvoiceNotFound)?;
+    /// * Option<Address> - Pending admin or None
+    pub fn get_pending_admin(env: Env) -> Option<Address> {
+        env.storage().instance().get(&DataKey::PendingAdmin)
+    }
+    
+    /// Grant a role to an account
+    /// 
+    /// # Arguments
+    /// * `role` - Role to grant (`Role::Admin` is transferred, not granted)
+    /// * `account` - Account receiving the role (admin must authorize)
+    pub fn grant_role(env: Env, role: Role, account: Address) -> Result<(), Error> {
+        let config = load_config(&env)?;
+        config.admin.require_auth();
         
-        // 3. Check status
-        if invoice.status != InvoiceStatus::Open {
-            return Err(Error::InvoiceNotOpen);
-        }
+        access::set_role(&env, role, &account, true)?;
         
-        // 4. Check expiry
-        let current_time = env.ledger().timestamp();
-        if current_time > invoice.expiry {
-            // Auto-expire
-            invoice.status = InvoiceStatus::Expired;
-            env.storage()
-                .persistent()
-                .set(&DataKey::Invoice(invoice_id.clone()), &invoice);
-            return Err(Error::InvoiceExpired);
-        }
+        env.events().publish(
+            (symbol_short!("granted"), account),
+            role,
+        );
         
-        // 5. Validate exact amount
-        if amount != invoice.amount {
-            return Err(Error::AmountMismatch);
-        }
+        Ok(())
+    }
+    
+    /// Revoke a role from an account
+    /// 
+    /// # Arguments
+    /// * `role` - Role to revoke
+    /// * `account` - Account losing the role (admin must authorize)
+    pub fn revoke_role(env: Env, role: Role, account: Address) -> Result<(), Error> {
+        let config = load_config(&env)?;
+        config.admin.require_auth();
         
-        // 6. Transfer USDC from payer to merchant
-        let usdc_address = get_usdc_address(&env);
-        let token_client = token::Client::new(&env, &usdc_address);
+        access::set_role(&env, role, &account, false)?;
         
-        token_client.transfer(
-            &payer,
-            &invoice.merchant,
-            &amount,
+        env.events().publish(
+            (symbol_short!("revoked"), account),
+            role,
         );
         
-        // 7. Update invoice status
-        invoice.status = InvoiceStatus::Paid;
-        invoice.payer = Some(payer.clone());
+        Ok(())
+    }
+    
+    /// Check whether an account holds a role
+    /// 
+    /// # Arguments
+    /// * `role` - Role to check
+    /// * `account` - Account to check
+    /// 
+    /// # Returns
+    /// * bool - true if the account holds the role (the admin holds all roles)
+    pub fn has_role(env: Env, role: Role, account: Address) -> Result<bool, Error> {
+        access::has_role(&env, role, &account)
+    }
+    
+    /// Add a SEP-41 token contract to the payment allowlist
+    /// 
+    /// # Arguments
+    /// * `caller` - Account holding `Role::Compliance` (must authorize)
+    /// * `token` - Token contract address
+    pub fn add_token(env: Env, caller: Address, token: Address) -> Result<(), Error> {
+        access::require_role(&env, &caller, Role::Compliance)?;
+        
         env.storage()
             .persistent()
-            .set(&DataKey::Invoice(invoice_id.clone()), &invoice);
+            .set(&DataKey::AllowedToken(token.clone()), &true);
         
-        // 8. Create payment record
-        let payment = Payment {
-            invoice_id: invoice_id.clone(),
-            payer: payer.clone(),
-            amount,
-            timestamp: current_time,
-        };
+        env.events().publish(
+            (symbol_short!("tok_add"), caller),
+            token,
+        );
+        
+        Ok(())
+    }
+    
+    /// Remove a token contract from the payment allowlist
+    /// 
+    /// Existing invoices in the token can no longer be paid, but paid ones
+    /// can still be refunded.
+    /// 
+    /// # Arguments
+    /// * `caller` - Account holding `Role::Compliance` (must authorize)
+    /// * `token` - Token contract address
+    pub fn remove_token(env: Env, caller: Address, token: Address) -> Result<(), Error> {
+        access::require_role(&env, &caller, Role::Compliance)?;
         
         env.storage()
             .persistent()
-            .set(&DataKey::Payment(invoice_id.clone()), &payment);
+            .remove(&DataKey::AllowedToken(token.clone()));
         
-        // 9. Emit event
         env.events().publish(
-            (symbol_short!("paid"), invoice.merchant.clone()),
-            (invoice_id, payer, amount),
+            (symbol_short!("tok_rm"), caller),
+            token,
         );
         
         Ok(())
     }
     
-    /// Refund a paid invoice
+    /// Check whether a token contract is on the payment allowlist
     /// 
     /// # Arguments
-    /// * `invoice_id` - The invoice to refund
-    /// * `merchant` - The merchant's address (must authorize and match invoice)
-    /// * `amount` - Refund amount (must equal payment amount for full refund)
+    /// * `token` - Token contract address
     /// 
     /// # Returns
-    /// * Ok(()) on success
-    pub fn refund(
+    /// * bool - true if invoices can be created and paid in this token
+    pub fn is_token_allowed(env: Env, token: Address) -> bool {
+        is_token_allowed(&env, &token)
+    }
+    
+    /// Set the policy applied to merchants without their own override
+    /// 
+    /// # Arguments
+    /// * `policy` - New default limits (admin must authorize)
+    pub fn set_default_policy(env: Env, policy: CheckoutPolicy) -> Result<(), Error> {
+        let mut config = load_config(&env)?;
+        config.admin.require_auth();
+        policy.validate()?;
+        
+        config.default_policy = policy.clone();
+        env.storage().instance().set(&DataKey::Config, &config);
+        
+        env.events().publish(
+            (symbol_short!("def_pol"), config.admin),
+            policy,
+        );
+        
+        Ok(())
+    }
+    
+    /// Override the checkout policy for a single merchant
+    /// 
+    /// # Arguments
+    /// * `caller` - Account holding `Role::Compliance` (must authorize)
+    /// * `merchant` - Merchant the policy applies to
+    /// * `policy` - Limits for this merchant
+    pub fn set_merchant_policy(
         env: Env,
-        invoice_id: BytesN<32>,
+        caller: Address,
         merchant: Address,
-        amount: i128,
+        policy: CheckoutPolicy,
     ) -> Result<(), Error> {
-        // 1. Require merchant authorization
-        merchant.require_auth();
+        access::require_role(&env, &caller, Role::Compliance)?;
+        policy.validate()?;
         
-        // 2. Load invoice
-        let mut invoice: Invoice = env
-            .storage()
+        env.storage()
             .persistent()
-            .get(&DataKey::Invoice(invoice_id.clone()))
-            .ok_or(Error::InvoiceNotFound)?;
+            .set(&DataKey::MerchantPolicy(merchant.clone()), &policy);
         
-        // 3. Verify merchant is invoice creator
-        if invoice.merchant != merchant {
-            return Err(Error::Unauthorized);
-        }
+        env.events().publish(
+            (symbol_short!("policy"), merchant),
+            Some(policy),
+        );
         
-        // 4. Check status (must be Paid)
-        if invoice.status != InvoiceStatus::Paid {
-            return Err(Error::InvoiceNotPaid);
-        }
+        Ok(())
+    }
+    
+    /// Remove a merchant's override so the default policy applies again
+    /// 
+    /// # Arguments
+    /// * `caller` - Account holding `Role::Compliance` (must authorize)
+    /// * `merchant` - Merchant whose override is removed
+    pub fn clear_merchant_policy(
+        env: Env,
+        caller: Address,
+        merchant: Address,
+    ) -> Result<(), Error> {
+        access::require_role(&env, &caller, Role::Compliance)?;
         
-        // 5. Get payment to find payer
-        let payment: Payment = env
-            .storage()
+        env.storage()
             .persistent()
-            .get(&DataKey::Payment(invoice_id.clone()))
-            .ok_or(Error::PaymentNotFound)?;
+            .remove(&DataKey::MerchantPolicy(merchant.clone()));
         
-        // 6. Validate refund amount (must be full refund)
-        if amount != payment.amount {
-            return Err(Error::AmountMismatch);
-        }
+        env.events().publish(
+            (symbol_short!("policy"), merchant),
+            None::<CheckoutPolicy>,
+        );
+        
+        Ok(())
+    }
+    
+    /// Get the policy `create_invoice` applies to a merchant
+    /// 
+    /// # Arguments
+    /// * `merchant` - Merchant to query
+    /// 
+    /// # Returns
+    /// * CheckoutPolicy - The merchant's override, or the default policy
+    pub fn get_merchant_policy(env: Env, merchant: Address) -> Result<CheckoutPolicy, Error> {
+        merchant_policy(&env, &merchant)
+    }
+    
+    /// Set or clear the platform fee taken from invoice payments
+    /// 
+    /// Applies to payments made from now on, including the refund setting,
+    /// which is recorded on each payment and governs all its refunds.
+    /// 
+    /// # Arguments
+    /// * `caller` - Account holding `Role::FeeManager` (must authorize)
+    /// * `fee` - New fee settings, or None to stop charging fees
+    pub fn set_fee_config(env: Env, caller: Address, fee: Option<FeeConfig>) -> Result<(), Error> {
+        access::require_role(&env, &caller, Role::FeeManager)?;
         
-        // 7. Transfer USDC from merchant back to payer
-        let usdc_address = get_usdc_address(&env);
-        let token_client = token::Client::new(&env, &usdc_address);
+        match &fee {
+            Some(fee) => {
+                fee.validate()?;
+                env.storage().instance().set(&DataKey::FeeConfig, fee);
+            }
+            None => env.storage().instance().remove(&DataKey::FeeConfig),
+        }
         
-        token_client.transfer(
-            &merchant,
-            &payment.payer,
-            &amount,
+        env.events().publish(
+            (symbol_short!("fee_cfg"), caller),
+            fee,
         );
         
-        // 8. Update invoice status
-        invoice.status = InvoiceStatus::Refunded;
+        Ok(())
+    }
+    
+    /// Get the platform fee settings
+    /// 
+    /// # Returns
+    /// * Option<FeeConfig> - Current settings, or None if no fee is charged
+    pub fn get_fee_config(env: Env) -> Option<FeeConfig> {
+        fee_config(&env)
+    }
+    
+    /// Replace the volume-based fee tiers of a token
+    /// 
+    /// Tiers are set per token because volume is counted per token, in its
+    /// smallest unit, so thresholds depend on the token's decimals.
+    /// 
+    /// # Arguments
+    /// * `caller` - Account holding `Role::FeeManager` (must authorize)
+    /// * `token` - Token whose payments the tiers apply to
+    /// * `tiers` - Up to `MAX_FEE_TIERS` tiers, ascending by `min_volume`
+    ///   (empty to charge `FeeConfig::bps` regardless of volume)
+    pub fn set_fee_tiers(
+        env: Env,
+        caller: Address,
+        token: Address,
+        tiers: Vec<FeeTier>,
+    ) -> Result<(), Error> {
+        access::require_role(&env, &caller, Role::FeeManager)?;
+        fees::validate_tiers(&tiers)?;
+        
         env.storage()
             .persistent()
-            .set(&DataKey::Invoice(invoice_id.clone()), &invoice);
+            .set(&DataKey::FeeTiers(token.clone()), &tiers);
         
-        // 9. Emit event
         env.events().publish(
-            (symbol_short!("refunded"), merchant.clone()),
-            (invoice_id, payment.payer.clone(), amount),
+            (symbol_short!("fee_tiers"), caller),
+            (token, tiers),
         );
         
         Ok(())
     }
     
-    /// Get full invoice details
+    /// Get the volume-based fee tiers of a token
     /// 
     /// # Arguments
-    /// * `invoice_id` - The invoice ID to query
+    /// * `token` - Token to query
     /// 
     /// # Returns
-    /// * Option<Invoice> - Invoice data or None if not found
-    pub fn get_invoice(
+    /// * Vec<FeeTier> - Tiers ascending by `min_volume` (empty if none)
+    pub fn get_fee_tiers(env: Env, token: Address) -> Vec<FeeTier> {
+        fees::fee_tiers(&env, &token)
+    }
+    
+    /// Set the minimum platform fee of a token
+    /// 
+    /// The minimum is set per token because it is an amount in the token's
+    /// smallest unit, so its value depends on the token's decimals. It is
+    /// charged once per invoice or link payment, spread over the
+    /// installments of an invoice, and never exceeds the payment.
+    /// 
+    /// # Arguments
+    /// * `caller` - Account holding `Role::FeeManager` (must authorize)
+    /// * `token` - Token whose payments the minimum applies to
+    /// * `min_fee` - Smallest fee, not negative (zero for none)
+    pub fn set_min_fee(
         env: Env,
-        invoice_id: BytesN<32>,
-    ) -> Option<Invoice> {
+        caller: Address,
+        token: Address,
+        min_fee: i128,
+    ) -> Result<(), Error> {
+        access::require_role(&env, &caller, Role::FeeManager)?;
+        if min_fee < 0 {
+            return Err(Error::InvalidFeeConfig);
+        }
+        
         env.storage()
             .persistent()
-            .get(&DataKey::Invoice(invoice_id))
+            .set(&DataKey::MinFee(token.clone()), &min_fee);
+        
+        env.events().publish(
+            (symbol_short!("min_fee"), caller),
+            (token, min_fee),
+        );
+        
+        Ok(())
     }
     
-    /// Get payment details for an invoice
+    /// Get the minimum platform fee of a token
     /// 
     /// # Arguments
-    /// * `invoice_id` - The invoice ID to query
+    /// * `token` - Token to query
     /// 
     /// # Returns
-    /// * Option<Payment> - Payment data or None if not found
-    pub fn get_payment(
+    /// * i128 - Smallest fee per invoice or link payment (zero if none)
+    pub fn get_min_fee(env: Env, token: Address) -> i128 {
+        fees::min_fee(&env, &token)
+    }
+    
+    /// Set or clear a merchant's negotiated fee rate
+    /// 
+    /// # Arguments
+    /// * `caller` - Account holding `Role::FeeManager` (must authorize)
+    /// * `merchant` - The merchant
+    /// * `bps` - Rate replacing the tiers for this merchant, or None
+    pub fn set_merchant_fee(
         env: Env,
-        invoice_id: BytesN<32>,
-    ) -> Option<Payment> {
-        env.storage()
-            .persistent()
-            .get(&DataKey::Payment(invoice_id))
+        caller: Address,
+        merchant: Address,
+        bps: Option<u32>,
+    ) -> Result<(), Error> {
+        access::require_role(&env, &caller, Role::FeeManager)?;
+        
+        let key = DataKey::MerchantFeeOverride(merchant.clone());
+        match bps {
+            Some(bps) if bps as i128 > BPS_DENOMINATOR => return Err(Error::InvalidFeeTier),
+            Some(bps) => env.storage().persistent().set(&key, &bps),
+            None => env.storage().persistent().remove(&key),
+        }
+        
+        env.events().publish(
+            (symbol_short!("fee_ovr"), merchant),
+            bps,
+        );
+        
+        Ok(())
     }
     
-    /// Get just the invoice status (convenience function)
+    /// Get a merchant's negotiated fee rate, if any
     /// 
     /// # Arguments
-    /// * `invoice_id` - The invoice ID to query
+    /// * `merchant` - The merchant
     /// 
     /// # Returns
-    /// * Option<InvoiceStatus> - Status or None if not found
-    pub fn get_invoice_status(
-        env: Env,
-        invoice_id: BytesN<32>,
-    ) -> Option<InvoiceStatus> {
-        let invoice: Option<Invoice> = env.storage()
-            .persistent()
-            .get(&DataKey::Invoice(invoice_id));
-        
-        invoice.map(|inv| inv.status)
+    /// * Option<u32> - Rate in basis points, or None if the tiers apply
+    pub fn get_merchant_fee(env: Env, merchant: Address) -> Option<u32> {
+        fees::merchant_override(&env, &merchant)
     }
-}
-
-/// Helper to get USDC contract address from storage
-fn get_usdc_address(env: &Env) -> Address {
-    env.storage()
-        .instance()
-        .get(&symbol_short!("USDC"))
-        .unwrap()
+    
+    /// Get a merchant's settled volume over the rolling 30-day window
+    /// 
+    /// Refunds are deducted from the day their payment settled.
+    /// 
+    /// # Arguments
+    /// * `merchant` - The merchant
+    /// * `token` - Token the volume is counted in
+    /// 
+    /// # Returns
+    /// * i128 - Gross amount settled, in the token's smallest unit
+    pub fn get_merchant_volume(env: Env, merchant: Address, token: Address) -> i128 {
+        fees::rolling_volume(&env, &merchant, &token)
+    }
+    
+    /// Configure how escrow disputes are decided
+    /// 
+    /// Arbiters are accounts granted `Role::Arbiter`. Disputes already open
+    /// keep the threshold they were opened with.
+    /// 
+    /// # Arguments
+    /// * `caller` - The admin (must authorize)
+    /// * `config` - Vote threshold and resolution window
+    pub fn set_arbitration(env: Env, caller: Address, config: ArbitrationConfig) -> Result<(), Error> {
+        access::require_role(&env, &caller, Role::Admin)?;
+        config.validate()?;
+        
+        env.storage().instance().set(&DataKey::ArbitrationConfig, &config);
+        
+        env.events().publish(
+            (symbol_short!("arb_cfg"), caller),
+            config,
+        );
+        
+        Ok(())
+    }
+    
+    /// Get the dispute settings
+    /// 
+    /// # Returns
+    /// * ArbitrationConfig - Current settings (the defaults if never set)
+    pub fn get_arbitration(env: Env) -> ArbitrationConfig {
+        disputes::arbitration_config(&env)
+    }
+    
+    /// Replace the contract code with a previously uploaded WASM
+    /// 
+    /// Storage written by the old code must afterwards be brought up to
+    /// date with `migrate` before invoices can be created, paid or refunded.
+    /// 
+    /// # Arguments
+    /// * `new_wasm_hash` - Hash of the uploaded WASM (admin must authorize)
+    pub fn upgrade(env: Env, new_wasm_hash: BytesN<32>) -> Result<(), Error> {
+        // The configuration may still be in the layout of the previous code
+        let (config, _) = migration::read_config(&env)?;
+        config.admin.require_auth();
+        
+        env.deployer().update_current_contract_wasm(new_wasm_hash.clone());
+        
+        env.events().publish(
+            (symbol_short!("upgraded"), config.admin),
+            new_wasm_hash,
+        );
+        
+        Ok(())
+    }
+    
+    /// Rewrite invoices stored in an older layout to the current one
+    /// 
+    /// Persistent entries cannot be enumerated on-chain, so the admin passes
+    /// the IDs of every invoice created before the upgrade, in one or more
+    /// batches. The batch with `complete` set records the new schema version
+    /// and re-enables the payment entrypoints. IDs that are unknown or
+    /// already migrated are skipped. The first batch also rewrites the
+    /// `Config`, which every other entrypoint needs in the current layout,
+    /// and adds its token to the allowlist.
+    /// 
+    /// Schema version 1 is the layout of contracts that store a `Config`
+    /// and have this entrypoint; deployments from before `initialize` took
+    /// an admin cannot be upgraded and must be redeployed.
+    /// 
+    /// # Arguments
+    /// * `invoice_ids` - Invoices to rewrite in this batch
+    /// * `complete` - Whether this is the last batch
+    /// 
+    /// # Returns
+    /// * Number of invoices rewritten
+    pub fn migrate(
+        env: Env,
+        invoice_ids: Vec<BytesN<32>>,
+        complete: bool,
+    ) -> Result<u32, Error> {
+        let (config, outdated) = migration::read_config(&env)?;
+        config.admin.require_auth();
+        
+        let from_version = schema_version(&env);
+        if from_version >= SCHEMA_VERSION {
+            return Err(Error::SchemaUpToDate);
+        }
+        
+        // Rewrite the configuration first, as invoices are migrated with
+        // it, and allow the token that version 1 accepted without a list
+        if outdated {
+            env.storage().instance().set(&DataKey::Config, &config);
+            env.storage()
+                .persistent()
+                .set(&DataKey::AllowedToken(config.token.clone()), &true);
+        }
+        
+        let mut migrated = 0;
+        for invoice_id in invoice_ids.iter() {
+            if migration::migrate_invoice(&env, from_version, &invoice_id)? {
+                migrated += 1;
+            }
+        }
+        
+        if complete {
+            env.storage().instance().set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+        }
+        
+        env.events().publish(
+            (symbol_short!("migrated"), config.admin),
+            (from_version, migrated, complete),
+        );
+        
+        Ok(migrated)
+    }
+    
+    /// Pause an operation class (circuit breaker)
+    /// 
+    /// # Arguments
+    /// * `caller` - Admin or operator (must authorize)
+    /// * `operation` - The class of operations to stop
+    pub fn pause(env: Env, caller: Address, operation: Operation) -> Result<(), Error> {
+        set_paused(&env, caller, operation, true)
+    }
+    
+    /// Resume a paused operation class
+    /// 
+    /// # Arguments
+    /// * `caller` - Admin or operator (must authorize)
+    /// * `operation` - The class of operations to resume
+    pub fn unpause(env: Env, caller: Address, operation: Operation) -> Result<(), Error> {
+        set_paused(&env, caller, operation, false)
+    }
+    
+    /// Check whether an operation class is paused
+    /// 
+    /// # Arguments
+    /// * `operation` - The class of operations to query
+    /// 
+    /// # Returns
+    /// * bool - true if calls in this class are currently rejected
+    pub fn is_paused(env: Env, operation: Operation) -> bool {
+        is_paused(&env, operation)
+    }
+    
+    /// Get the storage layout version currently in effect
+    /// 
+    /// # Returns
+    /// * u32 - Schema version (1 for storage written before versioning)
+    pub fn get_schema_version(env: Env) -> u32 {
+        schema_version(&env)
+    }
+    
+    /// Creates a new invoice for payment
+    /// 
+    /// # Arguments
+    /// * `merchant` - The merchant's address (must authorize this call)
+    /// * `token` - Allowlisted token contract the invoice is payable in
+    /// * `amount` - Amount in the token's smallest unit
+    /// * `expiry` - Unix timestamp when invoice expires
+    /// * `options` - Optional metadata, installment setting and designated payer
+    /// 
+    /// # Returns
+    /// * Invoice ID (BytesN<32>)
+    pub fn create_invoice(
+        env: Env,
+        merchant: Address,
+        token: Address,
+        amount: i128,
+        expiry: u64,
+        options: Option<InvoiceOptions>,
+    ) -> Result<BytesN<32>, Error> {
+        // 1. Require merchant authorization
+        merchant.require_auth();
+        
+        // 2. Generate unique invoice ID, then validate and store
+        let invoice_id = generate_invoice_id(&env, &merchant);
+        let breakdown = InvoiceBreakdown::flat(&env, amount);
+        open_invoice(&env, merchant, invoice_id, token, Pricing::Fixed(breakdown), expiry, options)
+    }
+    
+    /// Creates several invoices under a single merchant authorization
+    /// 
+    /// Every spec is validated like `create_invoice`; the first invalid one
+    /// fails the whole batch and nothing is stored.
+    /// 
+    /// # Arguments
+    /// * `merchant` - The merchant's address (must authorize this call)
+    /// * `specs` - 1 to `MAX_BATCH_SIZE` invoices to create
+    /// 
+    /// # Returns
+    /// * Invoice IDs, in the order of `specs`
+    pub fn create_invoices(
+        env: Env,
+        merchant: Address,
+        specs: Vec<InvoiceSpec>,
+    ) -> Result<Vec<BytesN<32>>, Error> {
+        // 1. Require merchant authorization once for the whole batch
+        merchant.require_auth();
+        
+        if specs.is_empty() || specs.len() > MAX_BATCH_SIZE {
+            return Err(Error::InvalidBatchSize);
+        }
+        
+        // 2. Create each invoice, stopping at the first invalid spec
+        let mut invoice_ids = Vec::new(&env);
+        for spec in specs.iter() {
+            let invoice_id = generate_invoice_id(&env, &merchant);
+            let breakdown = InvoiceBreakdown::flat(&env, spec.amount);
+            invoice_ids.push_back(open_invoice(
+                &env,
+                merchant.clone(),
+                invoice_id,
+                spec.token,
+                Pricing::Fixed(breakdown),
+                spec.expiry,
+                Some(spec.options),
+            )?);
+        }
+        
+        Ok(invoice_ids)
+    }
+    
+    /// Creates a new invoice whose amount is computed from line items
+    /// 
+    /// See `pricing::compute_breakdown` for the rounding rules. The stored
+    /// breakdown is returned by `get_invoice` for receipts.
+    /// 
+    /// # Arguments
+    /// * `merchant` - The merchant's address (must authorize this call)
+    /// * `token` - Allowlisted token contract the invoice is payable in
+    /// * `items` - 1 to 20 line items priced in the token's smallest unit
+    /// * `discount` - Optional invoice-level discount, applied before tax
+    /// * `expiry` - Unix timestamp when invoice expires
+    /// * `options` - Optional metadata, installment setting and designated payer
+    /// 
+    /// # Returns
+    /// * Invoice ID (BytesN<32>)
+    pub fn create_itemized_invoice(
+        env: Env,
+        merchant: Address,
+        token: Address,
+        items: Vec<LineItem>,
+        discount: Option<Discount>,
+        expiry: u64,
+        options: Option<InvoiceOptions>,
+    ) -> Result<BytesN<32>, Error> {
+        // 1. Require merchant authorization
+        merchant.require_auth();
+        
+        // 2. Derive the amount, then validate and store
+        let breakdown = pricing::compute_breakdown(&env, items, discount)?;
+        let invoice_id = generate_invoice_id(&env, &merchant);
+        open_invoice(&env, merchant, invoice_id, token, Pricing::Fixed(breakdown), expiry, options)
+    }
+    
+    /// Creates a new invoice whose ID is derived from the merchant's own reference
+    /// 
+    /// The ID is `sha256(xdr(contract) || xdr(merchant) || external_ref)`, so
+    /// it is known before the transaction confirms, and retrying with the
+    /// same reference fails with `Error::DuplicateInvoice` instead of
+    /// creating a second invoice.
+    /// 
+    /// # Arguments
+    /// * `merchant` - The merchant's address (must authorize this call)
+    /// * `external_ref` - Merchant reference, 1 to 64 bytes
+    /// * `token` - Allowlisted token contract the invoice is payable in
+    /// * `amount` - Amount in the token's smallest unit
+    /// * `expiry` - Unix timestamp when invoice expires
+    /// * `options` - Optional metadata, installment setting and designated payer
+    /// 
+    /// # Returns
+    /// * Invoice ID (BytesN<32>)
+    pub fn create_invoice_with_ref(
+        env: Env,
+        merchant: Address,
+        external_ref: Bytes,
+        token: Address,
+        amount: i128,
+        expiry: u64,
+        options: Option<InvoiceOptions>,
+    ) -> Result<BytesN<32>, Error> {
+        // 1. Require merchant authorization
+        merchant.require_auth();
+        
+        // 2. Validate the reference and derive the ID, then validate and store
+        if external_ref.is_empty() || external_ref.len() > MAX_EXTERNAL_REF_LEN {
+            return Err(Error::InvalidReference);
+        }
+        
+        let invoice_id = derive_invoice_id(&env, &merchant, &external_ref);
+        let breakdown = InvoiceBreakdown::flat(&env, amount);
+        open_invoice(&env, merchant, invoice_id, token, Pricing::Fixed(breakdown), expiry, options)
+    }
+    
+    /// Creates an invoice where the payer chooses the amount
+    /// 
+    /// Suited to tips and donations. Bounds left as `None` fall back to the
+    /// merchant's policy limits. Open-amount invoices cannot take
+    /// installments.
+    /// 
+    /// # Arguments
+    /// * `merchant` - The merchant's address (must authorize this call)
+    /// * `token` - Allowlisted token contract the invoice is payable in
+    /// * `min_amount` - Optional smallest accepted payment
+    /// * `max_amount` - Optional largest accepted payment
+    /// * `expiry` - Unix timestamp when invoice expires
+    /// * `options` - Optional metadata and designated payer
+    /// 
+    /// # Returns
+    /// * Invoice ID (BytesN<32>)
+    pub fn create_open_invoice(
+        env: Env,
+        merchant: Address,
+        token: Address,
+        min_amount: Option<i128>,
+        max_amount: Option<i128>,
+        expiry: u64,
+        options: Option<InvoiceOptions>,
+    ) -> Result<BytesN<32>, Error> {
+        // 1. Require merchant authorization
+        merchant.require_auth();
+        
+        // 2. Resolve the bounds, then validate and store
+        let policy = merchant_policy(&env, &merchant)?;
+        let min_amount = min_amount.unwrap_or(policy.min_amount);
+        let max_amount = max_amount.unwrap_or(policy.max_amount);
+        
+        let invoice_id = generate_invoice_id(&env, &merchant);
+        let pricing = Pricing::Range(min_amount, max_amount);
+        open_invoice(&env, merchant, invoice_id, token, pricing, expiry, options)
+    }
+    
+    /// Compute the ID `create_invoice_with_ref` would assign
+    /// 
+    /// # Arguments
+    /// * `merchant` - The merchant's address
+    /// * `external_ref` - Merchant reference
+    /// 
+    /// # Returns
+    /// * Invoice ID (BytesN<32>)
+    pub fn derive_invoice_id(env: Env, merchant: Address, external_ref: Bytes) -> BytesN<32> {
+        derive_invoice_id(&env, &merchant, &external_ref)
+    }
+    
+    /// Pay an invoice in its token
+    /// 
+    /// Open-amount invoices accept any amount within their range and record
+    /// it as the invoice amount. Invoices created with `allow_partial`
+    /// accept any amount up to the outstanding balance. Those installments
+    /// are held by the contract and forwarded to the merchant once the
+    /// invoice is fully paid, or returned to the payers by
+    /// `expire_invoices` if it lapses first. Escrow invoices keep the
+    /// payment in the contract until it is passed on by `release`.
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice to pay
+    /// * `payer` - The payer's address (must authorize)
+    /// * `amount` - Amount in the invoice token (must match invoice exactly
+    ///   unless it is open-amount or allows installments)
+    /// 
+    /// # Returns
+    /// * Ok(()) on success
+    pub fn pay(
+        env: Env,
+        invoice_id: BytesN<32>,
+        payer: Address,
+        amount: i128,
+    ) -> Result<(), Error> {
+        // 1. Require payer authorization
+        authorize_payment(&env, &payer)?;
+        
+        // 2. Validate and settle
+        pay_invoice(&env, invoice_id, payer, amount, None, None)
+    }
+    
+    /// Pay an invoice only if it is still at the revision the payer saw
+    /// 
+    /// Same as `pay`, but fails with `RevisionMismatch` if the merchant has
+    /// amended the invoice since it was shown to the payer.
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice to pay
+    /// * `payer` - The payer's address (must authorize)
+    /// * `amount` - Amount in the invoice token
+    /// * `revision` - Invoice revision the payer confirmed
+    /// 
+    /// # Returns
+    /// * Ok(()) on success
+    pub fn pay_revision(
+        env: Env,
+        invoice_id: BytesN<32>,
+        payer: Address,
+        amount: i128,
+        revision: u32,
+    ) -> Result<(), Error> {
+        // 1. Require payer authorization
+        authorize_payment(&env, &payer)?;
+        
+        // 2. Validate and settle
+        pay_invoice(&env, invoice_id, payer, amount, Some(revision), None)
+    }
+    
+    /// Pay several invoices, possibly from different merchants, at once
+    /// 
+    /// Each entry gets the same checks as `pay`. Either every invoice is
+    /// settled or the call fails and none is. Each `paid` event carries the
+    /// returned cart ID so merchants can correlate their orders. The cart is
+    /// refused if settling it could write more than `TX_WRITE_ENTRIES`
+    /// ledger entries (see `cart_writes`).
+    /// 
+    /// # Arguments
+    /// * `payer` - The payer's address (must authorize once for the cart)
+    /// * `items` - One or more (invoice ID, amount) pairs
+    /// 
+    /// # Returns
+    /// * Cart ID shared by the resulting `paid` events
+    pub fn pay_many(
+        env: Env,
+        payer: Address,
+        items: Vec<(BytesN<32>, i128)>,
+    ) -> Result<BytesN<32>, Error> {
+        // 1. Require payer authorization once for the whole cart
+        authorize_payment(&env, &payer)?;
+        
+        // 2. Check the cart fits in one transaction
+        if items.is_empty() || cart_writes(&env, &payer, &items)? > TX_WRITE_ENTRIES {
+            return Err(Error::InvalidBatchSize);
+        }
+        
+        // 3. Settle each invoice, failing the cart on the first error
+        let cart_id = generate_cart_id(&env, &payer, &items);
+        for (invoice_id, amount) in items.iter() {
+            pay_invoice(&env, invoice_id, payer.clone(), amount, None, Some(cart_id.clone()))?;
+        }
+        
+        Ok(cart_id)
+    }
+    
+    /// Refund part or all of a paid invoice
+    /// 
+    /// May be called any number of times until the refundable total has
+    /// been returned. Each refund is spread over the invoice's payments
+    /// oldest first, continuing where the previous refund stopped. For
+    /// invoices with a split table each recipient's share is clawed back in
+    /// proportion, which requires every recipient's authorization. Platform
+    /// fees are returned by the fee collector or kept, according to the
+    /// `FeeConfig::refund` setting each payment was made under. A refund
+    /// returning any fee therefore needs the collector's authorization as
+    /// well as the merchant's; payments made with no fee configured carry
+    /// none and the merchant alone authorizes. Funds still held in escrow are
+    /// returned in full from the contract, as no fee has been collected.
+    /// A payer who cannot receive the token is credited a claimable balance
+    /// instead, withdrawn later with `claim_refund`; the merchant's share is
+    /// then transferred to the contract, which the merchant authorizes in
+    /// place of the transfer to the payer.
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice to refund
+    /// * `merchant` - The merchant's address (must authorize and match invoice)
+    /// * `amount` - Amount returned to the payers, at most what remains of
+    ///   the amount paid (less fees if they were collected and are kept)
+    /// * `reason` - Reason code, stored on the refund record
+    /// 
+    /// # Returns
+    /// * Index of the new refund record
+    pub fn refund(
+        env: Env,
+        invoice_id: BytesN<32>,
+        merchant: Address,
+        amount: i128,
+        reason: u32,
+    ) -> Result<u32, Error> {
+        // 1. Require merchant authorization
+        merchant.require_auth();
+        load_config(&env)?;
+        require_current_schema(&env)?;
+        require_not_paused(&env, Operation::Refunds)?;
+        
+        // 2. Load invoice
+        let invoice = load_invoice(&env, &invoice_id)?;
+        
+        // 3. Verify merchant is invoice creator
+        if invoice.merchant != merchant {
+            return Err(Error::Unauthorized);
+        }
+        
+        // 4. Validate and transfer
+        issue_refund(&env, invoice, amount, reason)
+    }
+    
+    /// Set how long after payment the merchant's payers can request refunds
+    /// 
+    /// # Arguments
+    /// * `merchant` - The merchant's address (must authorize this call)
+    /// * `window_secs` - Seconds after an invoice's last payment, at most
+    ///   `MAX_REFUND_WINDOW_SECS` (0 to refuse all requests)
+    pub fn set_refund_window(env: Env, merchant: Address, window_secs: u64) -> Result<(), Error> {
+        merchant.require_auth();
+        load_config(&env)?;
+        
+        if window_secs > MAX_REFUND_WINDOW_SECS {
+            return Err(Error::InvalidPolicy);
+        }
+        
+        env.storage()
+            .persistent()
+            .set(&DataKey::RefundWindow(merchant.clone()), &window_secs);
+        
+        env.events().publish(
+            (symbol_short!("rfnd_win"), merchant),
+            window_secs,
+        );
+        
+        Ok(())
+    }
+    
+    /// Get a merchant's refund request window
+    /// 
+    /// # Arguments
+    /// * `merchant` - Merchant to query
+    /// 
+    /// # Returns
+    /// * u64 - Window in seconds (`DEFAULT_REFUND_WINDOW_SECS` if never set)
+    pub fn get_refund_window(env: Env, merchant: Address) -> u64 {
+        refund_window(&env, &merchant)
+    }
+    
+    /// Ask the merchant for a refund
+    /// 
+    /// Only an invoice paid entirely by `payer` can be the subject of a
+    /// request, so that the refund reaches the requester. Requests are
+    /// refused once the merchant's refund window after the last payment
+    /// has passed, and while an earlier request for the invoice is still
+    /// pending. The amount is checked again when the request is approved.
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice to refund
+    /// * `payer` - The invoice's payer (must authorize)
+    /// * `amount` - Amount requested, at most what remains refundable
+    /// * `reason` - Reason code, for the merchant
+    /// 
+    /// # Returns
+    /// * Index of the new request
+    pub fn request_refund(
+        env: Env,
+        invoice_id: BytesN<32>,
+        payer: Address,
+        amount: i128,
+        reason: u32,
+    ) -> Result<u32, Error> {
+        // 1. Require payer authorization
+        payer.require_auth();
+        load_config(&env)?;
+        require_current_schema(&env)?;
+        
+        // 2. Load invoice and check it was paid, entirely by this payer
+        let mut invoice = load_invoice(&env, &invoice_id)?;
+        if invoice.status != InvoiceStatus::Paid
+            && invoice.status != InvoiceStatus::Released
+            && invoice.status != InvoiceStatus::PartiallyRefunded
+        {
+            return Err(Error::InvoiceNotPaid);
+        }
+        let payments = load_payments(&env, &invoice)?;
+        if payments.iter().any(|payment| payment.payer != payer) {
+            return Err(Error::PayerNotAllowed);
+        }
+        
+        // 3. Check the merchant's refund window and the amount
+        let current_time = env.ledger().timestamp();
+        let paid_at = payments.last().map_or(invoice.created_at, |payment| payment.timestamp);
+        let window = refund_window(&env, &invoice.merchant);
+        if current_time >= paid_at.saturating_add(window) {
+            return Err(Error::RefundWindowClosed);
+        }
+        if amount <= 0 || amount > invoice.amount_paid - invoice.refunded_total {
+            return Err(Error::AmountMismatch);
+        }
+        
+        // 4. Allow one pending request at a time; as none can be made while
+        //    another waits, only the latest needs checking
+        if let Some(last) = invoice.request_count.checked_sub(1) {
+            let previous = load_refund_request(&env, &invoice_id, last)?;
+            if previous.status == RefundRequestStatus::Pending {
+                return Err(Error::RefundRequestPending);
+            }
+        }
+        
+        // 5. Store the request
+        let index = invoice.request_count;
+        let request = RefundRequest {
+            invoice_id: invoice_id.clone(),
+            payer: payer.clone(),
+            amount,
+            reason,
+            status: RefundRequestStatus::Pending,
+            requested_at: current_time,
+            decided_at: None,
+            refund_index: None,
+            rejection_reason: None,
+        };
+        env.storage()
+            .persistent()
+            .set(&DataKey::RefundRequest(invoice_id.clone(), index), &request);
+        
+        invoice.request_count += 1;
+        save_invoice(&env, &invoice);
+        
+        // 6. Emit event
+        env.events().publish(
+            (symbol_short!("refund_rq"), invoice.merchant),
+            (invoice_id, index, payer, amount, reason),
+        );
+        
+        Ok(index)
+    }
+    
+    /// Approve a pending refund request and refund the amount requested
+    /// 
+    /// The refund is made exactly as by `refund`, with the payer's reason
+    /// code, so the fee collector must also authorize if fees are returned.
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice the request is for
+    /// * `merchant` - The merchant's address (must authorize and match invoice)
+    /// * `index` - Index of the request, as returned by `request_refund`
+    /// 
+    /// # Returns
+    /// * Index of the new refund record
+    pub fn approve_refund(
+        env: Env,
+        invoice_id: BytesN<32>,
+        merchant: Address,
+        index: u32,
+    ) -> Result<u32, Error> {
+        // 1. Require merchant authorization
+        merchant.require_auth();
+        load_config(&env)?;
+        require_current_schema(&env)?;
+        require_not_paused(&env, Operation::Refunds)?;
+        
+        // 2. Load invoice and the pending request
+        let invoice = load_invoice(&env, &invoice_id)?;
+        if invoice.merchant != merchant {
+            return Err(Error::Unauthorized);
+        }
+        let mut request = load_refund_request(&env, &invoice_id, index)?;
+        if request.status != RefundRequestStatus::Pending {
+            return Err(Error::RequestNotPending);
+        }
+        
+        // 3. Refund and record the outcome
+        let refund_index = issue_refund(&env, invoice, request.amount, request.reason)?;
+        
+        request.status = RefundRequestStatus::Approved;
+        request.decided_at = Some(env.ledger().timestamp());
+        request.refund_index = Some(refund_index);
+        env.storage()
+            .persistent()
+            .set(&DataKey::RefundRequest(invoice_id.clone(), index), &request);
+        
+        // 4. Emit event
+        env.events().publish(
+            (symbol_short!("refund_ok"), merchant),
+            (invoice_id, index, refund_index),
+        );
+        
+        Ok(refund_index)
+    }
+    
+    /// Turn down a pending refund request
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice the request is for
+    /// * `merchant` - The merchant's address (must authorize and match invoice)
+    /// * `index` - Index of the request, as returned by `request_refund`
+    /// * `reason` - Reason code, for the payer
+    /// 
+    /// # Returns
+    /// * Ok(()) on success
+    pub fn reject_refund(
+        env: Env,
+        invoice_id: BytesN<32>,
+        merchant: Address,
+        index: u32,
+        reason: u32,
+    ) -> Result<(), Error> {
+        // 1. Require merchant authorization
+        merchant.require_auth();
+        load_config(&env)?;
+        require_current_schema(&env)?;
+        
+        // 2. Load invoice and the pending request
+        let invoice = load_invoice(&env, &invoice_id)?;
+        if invoice.merchant != merchant {
+            return Err(Error::Unauthorized);
+        }
+        let mut request = load_refund_request(&env, &invoice_id, index)?;
+        if request.status != RefundRequestStatus::Pending {
+            return Err(Error::RequestNotPending);
+        }
+        
+        // 3. Record the decision
+        request.status = RefundRequestStatus::Rejected;
+        request.decided_at = Some(env.ledger().timestamp());
+        request.rejection_reason = Some(reason);
+        env.storage()
+            .persistent()
+            .set(&DataKey::RefundRequest(invoice_id.clone(), index), &request);
+        
+        // 4. Emit event
+        env.events().publish(
+            (symbol_short!("refund_no"), merchant),
+            (invoice_id, index, reason),
+        );
+        
+        Ok(())
+    }
+    
+    /// Withdraw refunds credited to a payer who could not receive them
+    /// 
+    /// Claims are allowed while refunds are paused, as the funds are
+    /// already owed to the payer and held apart from everything else.
+    /// 
+    /// # Arguments
+    /// * `payer` - The payer's address (must authorize)
+    /// * `token` - Token the refunds were made in
+    /// 
+    /// # Returns
+    /// * Amount transferred to the payer (0 if nothing was owed)
+    pub fn claim_refund(env: Env, payer: Address, token: Address) -> Result<i128, Error> {
+        // 1. Require payer authorization
+        payer.require_auth();
+        load_config(&env)?;
+        require_current_schema(&env)?;
+        
+        // 2. Clear the balance and the matching liability
+        let key = DataKey::Claimable(payer.clone(), token.clone());
+        let amount: i128 = env.storage().persistent().get(&key).unwrap_or(0);
+        if amount == 0 {
+            return Ok(0);
+        }
+        env.storage().persistent().remove(&key);
+        
+        let total_key = DataKey::ClaimableTotal(token.clone());
+        let total: i128 = env.storage().persistent().get(&total_key).unwrap_or(0);
+        env.storage().persistent().set(&total_key, &(total - amount));
+        
+        // 3. Transfer from the contract
+        token::Client::new(&env, &token).transfer(&env.current_contract_address(), &payer, &amount);
+        
+        // 4. Emit event
+        env.events().publish(
+            (symbol_short!("claimed"), payer),
+            (token, amount),
+        );
+        
+        Ok(amount)
+    }
+    
+    /// Release the funds of an escrow invoice to the merchant
+    /// 
+    /// The payer may release at any time, confirming delivery. Once the
+    /// release delay has passed anyone may release. The net amount is paid
+    /// out like a direct payment, including any split table, and the
+    /// platform fee goes to its collector. A disputed escrow is frozen until
+    /// its dispute deadline; if the arbiters have not decided by then, the
+    /// dispute lapses and anyone may release.
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The escrow invoice to release
+    /// * `caller` - The payer, or any account once `release_at` is reached
+    ///   or the dispute has lapsed (must authorize)
+    /// 
+    /// # Returns
+    /// * Ok(()) on success
+    pub fn release(env: Env, invoice_id: BytesN<32>, caller: Address) -> Result<(), Error> {
+        // 1. Require caller authorization
+        caller.require_auth();
+        load_config(&env)?;
+        require_current_schema(&env)?;
+        require_not_paused(&env, Operation::Payments)?;
+        
+        // 2. Load the invoice and its escrow
+        let mut invoice = load_invoice(&env, &invoice_id)?;
+        let disputed = invoice.status == InvoiceStatus::Disputed;
+        if invoice.status != InvoiceStatus::Escrowed && !disputed {
+            return Err(Error::EscrowNotHeld);
+        }
+        let escrow = load_escrow(&env, &invoice_id)?;
+        
+        // 3. A dispute blocks release until its deadline, then lapses
+        let current_time = env.ledger().timestamp();
+        if disputed {
+            let mut dispute = disputes::load_dispute(&env, &invoice_id)?;
+            if current_time < dispute.deadline {
+                return Err(Error::DisputePending);
+            }
+            dispute.status = DisputeStatus::Lapsed;
+            disputes::save_dispute(&env, &dispute);
+        }
+        
+        // 4. Otherwise only the payer may release before the release time
+        if !disputed && caller != escrow.payer && current_time < escrow.release_at {
+            return Err(Error::ReleaseNotDue);
+        }
+        
+        // 5. Pass the funds on from the contract
+        let net = settle_escrow(&env, &mut invoice, &escrow, 0)?;
+        
+        // 6. Update invoice status
+        invoice.status = InvoiceStatus::Released;
+        invoice.updated_at = current_time;
+        save_invoice(&env, &invoice);
+        
+        // 7. Emit event
+        env.events().publish(
+            (symbol_short!("released"), invoice.merchant),
+            (invoice_id, caller, net),
+        );
+        
+        Ok(())
+    }
+    
+    /// Dispute an escrow invoice, freezing its funds
+    /// 
+    /// Must be opened before the escrow's release time. Arbiters then vote
+    /// with `vote_dispute` until the dispute deadline.
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The escrowed invoice to dispute
+    /// * `payer` - The escrow's payer (must authorize)
+    /// * `reason` - Reason code, for the arbiters
+    /// 
+    /// # Returns
+    /// * Unix timestamp of the dispute deadline
+    pub fn open_dispute(
+        env: Env,
+        invoice_id: BytesN<32>,
+        payer: Address,
+        reason: u32,
+    ) -> Result<u64, Error> {
+        // 1. Require payer authorization
+        payer.require_auth();
+        load_config(&env)?;
+        require_current_schema(&env)?;
+        
+        // 2. Load the invoice and its escrow, and check it is the payer's
+        let mut invoice = load_invoice(&env, &invoice_id)?;
+        if invoice.status != InvoiceStatus::Escrowed {
+            return Err(Error::EscrowNotHeld);
+        }
+        let escrow = load_escrow(&env, &invoice_id)?;
+        if escrow.payer != payer {
+            return Err(Error::Unauthorized);
+        }
+        
+        // 3. Check the release window is still open
+        let current_time = env.ledger().timestamp();
+        if current_time >= escrow.release_at {
+            return Err(Error::DisputeWindowClosed);
+        }
+        
+        // 4. Store the dispute under the current arbitration settings
+        let config = disputes::arbitration_config(&env);
+        let dispute = Dispute {
+            invoice_id: invoice_id.clone(),
+            payer: payer.clone(),
+            reason,
+            status: DisputeStatus::Open,
+            opened_at: current_time,
+            deadline: current_time.saturating_add(config.resolution_secs),
+            threshold: config.threshold,
+            votes: Vec::new(&env),
+            payer_amount: 0,
+        };
+        disputes::save_dispute(&env, &dispute);
+        
+        invoice.status = InvoiceStatus::Disputed;
+        invoice.updated_at = current_time;
+        save_invoice(&env, &invoice);
+        
+        // 5. Emit event
+        env.events().publish(
+            (symbol_short!("disputed"), invoice.merchant),
+            (invoice_id, payer, reason, dispute.deadline),
+        );
+        
+        Ok(dispute.deadline)
+    }
+    
+    /// Vote on how to divide the funds of a disputed escrow
+    /// 
+    /// Once `threshold` arbiters have voted for the same `payer_amount`,
+    /// that amount is returned to the payer and the rest is paid out to the
+    /// merchant as on release, less a proportional share of the platform fee.
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The disputed invoice
+    /// * `arbiter` - Account granted `Role::Arbiter` (must authorize); the
+    ///   admin does not hold this role implicitly
+    /// * `payer_amount` - Part of the escrowed amount to return to the payer,
+    ///   from 0 (all to the merchant) to the full amount
+    /// 
+    /// # Returns
+    /// * true if this vote resolved the dispute
+    pub fn vote_dispute(
+        env: Env,
+        invoice_id: BytesN<32>,
+        arbiter: Address,
+        payer_amount: i128,
+    ) -> Result<bool, Error> {
+        // 1. Require arbiter authorization; a resolution settles the
+        //    escrow, so it stops with payments like `release`
+        access::require_role(&env, &arbiter, Role::Arbiter)?;
+        require_current_schema(&env)?;
+        require_not_paused(&env, Operation::Payments)?;
+        
+        // 2. Load the dispute and check it still takes votes
+        let mut dispute = disputes::load_dispute(&env, &invoice_id)?;
+        if dispute.status != DisputeStatus::Open {
+            return Err(Error::DisputeNotOpen);
+        }
+        let current_time = env.ledger().timestamp();
+        if current_time >= dispute.deadline {
+            return Err(Error::DisputeDeadlinePassed);
+        }
+        
+        // 3. Validate and record the vote
+        let escrow = load_escrow(&env, &invoice_id)?;
+        if payer_amount < 0 || payer_amount > escrow.amount {
+            return Err(Error::InvalidResolution);
+        }
+        if dispute.votes.iter().any(|vote| vote.arbiter == arbiter) {
+            return Err(Error::AlreadyVoted);
+        }
+        dispute.votes.push_back(DisputeVote { arbiter: arbiter.clone(), payer_amount });
+        
+        env.events().publish(
+            (symbol_short!("dsp_vote"), arbiter),
+            (invoice_id.clone(), payer_amount),
+        );
+        
+        // 4. Divide the funds once enough arbiters agree
+        if disputes::votes_for(&dispute, payer_amount) < dispute.threshold {
+            disputes::save_dispute(&env, &dispute);
+            return Ok(false);
+        }
+        
+        let mut invoice = load_invoice(&env, &invoice_id)?;
+        let merchant_net = settle_escrow(&env, &mut invoice, &escrow, payer_amount)?;
+        
+        dispute.status = DisputeStatus::Resolved;
+        dispute.payer_amount = payer_amount;
+        disputes::save_dispute(&env, &dispute);
+        
+        invoice.status = InvoiceStatus::Resolved;
+        invoice.updated_at = current_time;
+        save_invoice(&env, &invoice);
+        
+        // 5. Emit event
+        env.events().publish(
+            (symbol_short!("resolved"), invoice.merchant),
+            (invoice_id, payer_amount, merchant_net),
+        );
+        
+        Ok(true)
+    }
+    
+    /// Void an invoice that has not been paid
+    /// 
+    /// Installments already received on a partially paid invoice are
+    /// returned to their payers, so such an invoice cannot be cancelled
+    /// while refunds are paused.
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice to cancel
+    /// * `merchant` - The merchant's address (must authorize and match invoice)
+    /// 
+    /// # Returns
+    /// * Ok(()) on success
+    pub fn cancel_invoice(
+        env: Env,
+        invoice_id: BytesN<32>,
+        merchant: Address,
+    ) -> Result<(), Error> {
+        // 1. Require merchant authorization
+        merchant.require_auth();
+        load_config(&env)?;
+        require_current_schema(&env)?;
+        require_not_paused(&env, Operation::Invoicing)?;
+        
+        // 2. Load invoice and verify ownership
+        let mut invoice = load_invoice(&env, &invoice_id)?;
+        if invoice.merchant != merchant {
+            return Err(Error::Unauthorized);
+        }
+        
+        // 3. Only invoices still awaiting payment can be cancelled
+        let current_time = env.ledger().timestamp();
+        if !invoice.is_awaiting_payment() {
+            return Err(Error::InvoiceNotOpen);
+        }
+        if invoice.status_at(current_time) == InvoiceStatus::Expired {
+            return Err(Error::InvoiceExpired);
+        }
+        
+        // 4. Return any installments and update status
+        if invoice.amount_paid > 0 {
+            require_not_paused(&env, Operation::Refunds)?;
+            refund_installments(&env, &invoice);
+        }
+        
+        invoice.status = InvoiceStatus::Cancelled;
+        invoice.updated_at = current_time;
+        save_invoice(&env, &invoice);
+        
+        // 5. Emit event
+        env.events().publish(
+            (symbol_short!("cancelled"), merchant),
+            invoice_id,
+        );
+        
+        Ok(())
+    }
+    
+    /// Change the amount or extend the expiry of an unpaid invoice
+    /// 
+    /// Each amendment bumps `revision`; payers can pin the revision they
+    /// were shown with `pay_revision`. A new amount replaces any line-item
+    /// breakdown with a flat one.
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice to amend
+    /// * `merchant` - The merchant's address (must authorize and match invoice)
+    /// * `new_amount` - Optional new amount (fixed-amount invoices only)
+    /// * `new_expiry` - Optional later expiry, within the policy window
+    /// 
+    /// # Returns
+    /// * The invoice's new revision number
+    pub fn amend_invoice(
+        env: Env,
+        invoice_id: BytesN<32>,
+        merchant: Address,
+        new_amount: Option<i128>,
+        new_expiry: Option<u64>,
+    ) -> Result<u32, Error> {
+        // 1. Require merchant authorization
+        merchant.require_auth();
+        let policy = merchant_policy(&env, &merchant)?;
+        require_current_schema(&env)?;
+        require_not_paused(&env, Operation::Invoicing)?;
+        
+        // 2. Load invoice and verify ownership
+        let mut invoice = load_invoice(&env, &invoice_id)?;
+        if invoice.merchant != merchant {
+            return Err(Error::Unauthorized);
+        }
+        
+        // 3. Only open invoices without any payment can be amended
+        let current_time = env.ledger().timestamp();
+        if invoice.status != InvoiceStatus::Open {
+            return Err(Error::InvoiceNotOpen);
+        }
+        if invoice.status_at(current_time) == InvoiceStatus::Expired {
+            return Err(Error::InvoiceExpired);
+        }
+        if new_amount.is_none() && new_expiry.is_none() {
+            return Err(Error::InvalidAmendment);
+        }
+        
+        // 4. Validate and apply the changes
+        if let Some(amount) = new_amount {
+            if invoice.amount_mode != AmountMode::Fixed
+                || amount <= 0
+                || amount < policy.min_amount
+                || amount > policy.max_amount
+            {
+                return Err(Error::InvalidAmount);
+            }
+            invoice.amount = amount;
+            invoice.breakdown = InvoiceBreakdown::flat(&env, amount);
+        }
+        
+        if let Some(expiry) = new_expiry {
+            let max_expiry = current_time.saturating_add(policy.max_expiry_secs);
+            if expiry <= invoice.expiry || expiry > max_expiry {
+                return Err(Error::InvalidExpiry);
+            }
+            invoice.expiry = expiry;
+        }
+        
+        invoice.revision += 1;
+        invoice.updated_at = current_time;
+        save_invoice(&env, &invoice);
+        
+        // 5. Emit event
+        env.events().publish(
+            (symbol_short!("amended"), merchant),
+            (invoice_id, invoice.revision, invoice.amount, invoice.expiry),
+        );
+        
+        Ok(invoice.revision)
+    }
+    
+    /// Mark lapsed open invoices as expired
+    /// 
+    /// Anyone may call this. IDs that are unknown, not awaiting payment, or
+    /// not yet past their expiry are skipped. Installments already received
+    /// on a partially paid invoice are returned to their payers; while
+    /// refunds are paused such invoices are skipped too.
+    /// 
+    /// # Arguments
+    /// * `invoice_ids` - Invoices to check
+    /// 
+    /// # Returns
+    /// * Number of invoices moved to `Expired`
+    pub fn expire_invoices(env: Env, invoice_ids: Vec<BytesN<32>>) -> Result<u32, Error> {
+        load_config(&env)?;
+        require_current_schema(&env)?;
+        
+        let current_time = env.ledger().timestamp();
+        let refunds_paused = is_paused(&env, Operation::Refunds);
+        let mut expired = 0;
+        
+        for invoice_id in invoice_ids.iter() {
+            let key = DataKey::Invoice(invoice_id.clone());
+            let Some(mut invoice) = env.storage().persistent().get::<_, Invoice>(&key) else {
+                continue;
+            };
+            if !invoice.is_awaiting_payment()
+                || invoice.status_at(current_time) != InvoiceStatus::Expired
+            {
+                continue;
+            }
+            
+            if invoice.amount_paid > 0 {
+                if refunds_paused {
+                    continue;
+                }
+                refund_installments(&env, &invoice);
+            }
+            
+            invoice.status = InvoiceStatus::Expired;
+            invoice.updated_at = current_time;
+            env.storage().persistent().set(&key, &invoice);
+            
+            env.events().publish(
+                (symbol_short!("expired"), invoice.merchant),
+                invoice_id,
+            );
+            expired += 1;
+        }
+        
+        Ok(expired)
+    }
+    
+    /// Creates a reusable payment link for a fixed amount
+    /// 
+    /// # Arguments
+    /// * `merchant` - The merchant's address (must authorize this call)
+    /// * `token` - Allowlisted token contract the link is payable in
+    /// * `amount` - Amount charged per use, in the token's smallest unit
+    /// * `max_uses` - Optional cap on the number of payments (at least 1)
+    /// * `end_date` - Optional Unix timestamp after which payments stop
+    /// * `metadata` - Optional order ID, description and line-item hash
+    /// 
+    /// # Returns
+    /// * Link ID (BytesN<32>)
+    pub fn create_payment_link(
+        env: Env,
+        merchant: Address,
+        token: Address,
+        amount: i128,
+        max_uses: Option<u32>,
+        end_date: Option<u64>,
+        metadata: Option<InvoiceMetadata>,
+    ) -> Result<BytesN<32>, Error> {
+        // 1. Require merchant authorization and check contract state
+        merchant.require_auth();
+        let policy = merchant_policy(&env, &merchant)?;
+        require_current_schema(&env)?;
+        require_not_paused(&env, Operation::Invoicing)?;
+        
+        // 2. Validate token, amount and usage limits
+        if !is_token_allowed(&env, &token) {
+            return Err(Error::TokenNotAllowed);
+        }
+        
+        if amount <= 0 || amount < policy.min_amount || amount > policy.max_amount {
+            return Err(Error::InvalidAmount);
+        }
+        
+        let current_time = env.ledger().timestamp();
+        if max_uses == Some(0) || end_date.is_some_and(|end| end <= current_time) {
+            return Err(Error::InvalidUsageLimit);
+        }
+        
+        let metadata = metadata.unwrap_or_else(InvoiceMetadata::empty);
+        metadata.validate()?;
+        
+        // 3. Create and store the link; IDs share the merchant's invoice sequence
+        let link_id = generate_invoice_id(&env, &merchant);
+        let link = PaymentLink {
+            id: link_id.clone(),
+            merchant: merchant.clone(),
+            token: token.clone(),
+            amount,
+            max_uses,
+            end_date,
+            use_count: 0,
+            active: true,
+            created_at: current_time,
+            metadata,
+        };
+        
+        env.storage()
+            .persistent()
+            .set(&DataKey::PaymentLink(link_id.clone()), &link);
+        
+        // 4. Emit event
+        env.events().publish(
+            (symbol_short!("link_new"), merchant),
+            (link_id.clone(), token, amount, max_uses, end_date),
+        );
+        
+        Ok(link_id)
+    }
+    
+    /// Pay a payment link once
+    /// 
+    /// # Arguments
+    /// * `link_id` - The link to pay
+    /// * `payer` - The payer's address (must authorize)
+    /// * `amount` - Amount in the link token (must match the link exactly)
+    /// 
+    /// # Returns
+    /// * Index of the payment record under the link
+    pub fn pay_link(
+        env: Env,
+        link_id: BytesN<32>,
+        payer: Address,
+        amount: i128,
+    ) -> Result<u32, Error> {
+        // 1. Require payer authorization
+        payer.require_auth();
+        load_config(&env)?;
+        require_current_schema(&env)?;
+        require_not_paused(&env, Operation::Payments)?;
+        
+        // 2. Load link and check it still accepts payments
+        let key = DataKey::PaymentLink(link_id.clone());
+        let mut link: PaymentLink = env.storage()
+            .persistent()
+            .get(&key)
+            .ok_or(Error::LinkNotFound)?;
+        
+        let current_time = env.ledger().timestamp();
+        if !link.is_usable_at(current_time) {
+            return Err(Error::LinkInactive);
+        }
+        
+        // 3. Validate amount and token
+        if amount != link.amount {
+            return Err(Error::AmountMismatch);
+        }
+        if !is_token_allowed(&env, &link.token) {
+            return Err(Error::TokenNotAllowed);
+        }
+        
+        // 4. Transfer from payer to merchant, less the platform fee
+        let token_client = token::Client::new(&env, &link.token);
+        let (fee, collector, fee_source) =
+            fees::platform_fee(&env, &link.merchant, &link.token, amount, 0, amount)?;
+        if let Some(collector) = collector.filter(|_| fee > 0) {
+            token_client.transfer(&payer, &collector, &fee);
+            env.events().publish(
+                (symbol_short!("fee"), collector),
+                (link_id.clone(), fee),
+            );
+        }
+        token_client.transfer(&payer, &link.merchant, &(amount - fee));
+        fees::record_volume(&env, &link.merchant, &link.token, amount);
+        
+        // 5. Record the payment under the link
+        let index = link.use_count;
+        let payment = Payment {
+            invoice_id: link_id.clone(),
+            payer: payer.clone(),
+            amount,
+            fee,
+            net: amount - fee,
+            fee_refund: fee_refund_mode(&env),
+            timestamp: current_time,
+        };
+        
+        env.storage()
+            .persistent()
+            .set(&DataKey::LinkPayment(link_id.clone(), index), &payment);
+        
+        link.use_count += 1;
+        env.storage().persistent().set(&key, &link);
+        
+        // 6. Emit event
+        env.events().publish(
+            (symbol_short!("link_paid"), link.merchant),
+            (link_id, payer, amount, index, fee_source),
+        );
+        
+        Ok(index)
+    }
+    
+    /// Stop a payment link from accepting further payments
+    /// 
+    /// # Arguments
+    /// * `link_id` - The link to deactivate
+    /// * `merchant` - The merchant's address (must authorize and match link)
+    /// 
+    /// # Returns
+    /// * Ok(()) on success
+    pub fn deactivate_link(
+        env: Env,
+        link_id: BytesN<32>,
+        merchant: Address,
+    ) -> Result<(), Error> {
+        merchant.require_auth();
+        
+        let key = DataKey::PaymentLink(link_id.clone());
+        let mut link: PaymentLink = env.storage()
+            .persistent()
+            .get(&key)
+            .ok_or(Error::LinkNotFound)?;
+        
+        if link.merchant != merchant {
+            return Err(Error::Unauthorized);
+        }
+        if !link.active {
+            return Err(Error::LinkInactive);
+        }
+        
+        link.active = false;
+        env.storage().persistent().set(&key, &link);
+        
+        env.events().publish(
+            (symbol_short!("link_off"), merchant),
+            link_id,
+        );
+        
+        Ok(())
+    }
+    
+    /// Get full invoice details
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice ID to query
+    /// 
+    /// # Returns
+    /// * Option<Invoice> - Invoice data (with expiry-aware status) or None if not found
+    pub fn get_invoice(
+        env: Env,
+        invoice_id: BytesN<32>,
+    ) -> Option<Invoice> {
+        let invoice: Option<Invoice> = env.storage()
+            .persistent()
+            .get(&DataKey::Invoice(invoice_id));
+        let current_time = env.ledger().timestamp();
+        
+        invoice.map(|mut inv| {
+            inv.status = inv.status_at(current_time);
+            inv
+        })
+    }
+    
+    /// Get a single payment made towards an invoice
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice ID to query
+    /// * `index` - Zero-based payment index, in the order payments were made
+    /// 
+    /// # Returns
+    /// * Option<Payment> - Payment data or None if not found
+    pub fn get_payment(
+        env: Env,
+        invoice_id: BytesN<32>,
+        index: u32,
+    ) -> Option<Payment> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Payment(invoice_id, index))
+    }
+    
+    /// Get every payment made towards an invoice
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice ID to query
+    /// 
+    /// # Returns
+    /// * Vec<Payment> - Payments in the order they were made (empty if the
+    ///   invoice does not exist)
+    pub fn get_payments(env: Env, invoice_id: BytesN<32>) -> Vec<Payment> {
+        let invoice: Option<Invoice> = env.storage()
+            .persistent()
+            .get(&DataKey::Invoice(invoice_id));
+        
+        match invoice {
+            Some(invoice) => load_payments(&env, &invoice).unwrap_or_else(|_| Vec::new(&env)),
+            None => Vec::new(&env),
+        }
+    }
+    
+    /// Get every refund made on an invoice
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice ID to query
+    /// 
+    /// # Returns
+    /// * Vec<Refund> - Refunds in the order they were made (empty if the
+    ///   invoice does not exist)
+    pub fn get_refunds(env: Env, invoice_id: BytesN<32>) -> Vec<Refund> {
+        let mut refunds = Vec::new(&env);
+        let Ok(invoice) = load_invoice(&env, &invoice_id) else {
+            return refunds;
+        };
+        
+        for index in 0..invoice.refund_count {
+            let refund: Option<Refund> = env.storage()
+                .persistent()
+                .get(&DataKey::Refund(invoice_id.clone(), index));
+            if let Some(refund) = refund {
+                refunds.push_back(refund);
+            }
+        }
+        refunds
+    }
+    
+    /// Get a refund request made on an invoice
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice ID to query
+    /// * `index` - Zero-based request index, as returned by `request_refund`
+    /// 
+    /// # Returns
+    /// * Option<RefundRequest> - Request data or None if not found
+    pub fn get_refund_request(env: Env, invoice_id: BytesN<32>, index: u32) -> Option<RefundRequest> {
+        load_refund_request(&env, &invoice_id, index).ok()
+    }
+    
+    /// Get the refunds a payer can withdraw with `claim_refund`
+    /// 
+    /// # Arguments
+    /// * `payer` - The payer to query
+    /// * `token` - Token the refunds were made in
+    /// 
+    /// # Returns
+    /// * i128 - Claimable balance (0 if none)
+    pub fn get_claimable(env: Env, payer: Address, token: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Claimable(payer, token))
+            .unwrap_or(0)
+    }
+    
+    /// Get the contract's outstanding refund liabilities in a token
+    /// 
+    /// # Arguments
+    /// * `token` - Token to query
+    /// 
+    /// # Returns
+    /// * i128 - Sum of all claimable balances in `token`
+    pub fn get_claimable_total(env: Env, token: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::ClaimableTotal(token))
+            .unwrap_or(0)
+    }
+    
+    /// Get just the invoice status (convenience function)
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice ID to query
+    /// 
+    /// # Returns
+    /// * Option<InvoiceStatus> - Expiry-aware status or None if not found
+    pub fn get_invoice_status(
+        env: Env,
+        invoice_id: BytesN<32>,
+    ) -> Option<InvoiceStatus> {
+        let invoice: Option<Invoice> = env.storage()
+            .persistent()
+            .get(&DataKey::Invoice(invoice_id));
+        let current_time = env.ledger().timestamp();
+        
+        invoice.map(|inv| inv.status_at(current_time))
+    }
+    
+    /// Get payment link details
+    /// 
+    /// # Arguments
+    /// * `link_id` - The link ID to query
+    /// 
+    /// # Returns
+    /// * Option<PaymentLink> - Link data or None if not found
+    pub fn get_payment_link(env: Env, link_id: BytesN<32>) -> Option<PaymentLink> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::PaymentLink(link_id))
+    }
+    
+    /// Get the escrow of a paid escrow invoice
+    /// 
+    /// The record is kept after the funds are released or refunded; the
+    /// invoice status tells which.
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice ID to query
+    /// 
+    /// # Returns
+    /// * Option<Escrow> - Escrow data or None if the invoice holds none
+    pub fn get_escrow(env: Env, invoice_id: BytesN<32>) -> Option<Escrow> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Escrow(invoice_id))
+    }
+    
+    /// Get the dispute of an escrow invoice
+    /// 
+    /// # Arguments
+    /// * `invoice_id` - The invoice ID to query
+    /// 
+    /// # Returns
+    /// * Option<Dispute> - Dispute data or None if the invoice was never disputed
+    pub fn get_dispute(env: Env, invoice_id: BytesN<32>) -> Option<Dispute> {
+        disputes::load_dispute(&env, &invoice_id).ok()
+    }
+    
+    /// Get a single payment made through a payment link
+    /// 
+    /// # Arguments
+    /// * `link_id` - The link ID to query
+    /// * `index` - Zero-based payment index, as returned by `pay_link`
+    /// 
+    /// # Returns
+    /// * Option<Payment> - Payment data or None if not found
+    pub fn get_link_payment(env: Env, link_id: BytesN<32>, index: u32) -> Option<Payment> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::LinkPayment(link_id, index))
+    }
+}
+
+/// Helper to check the payer's authorization and that payments are open
+fn authorize_payment(env: &Env, payer: &Address) -> Result<(), Error> {
+    payer.require_auth();
+    load_config(env)?;
+    require_current_schema(env)?;
+    require_not_paused(env, Operation::Payments)
+}
+
+/// Helper behind `pay`, `pay_revision` and `pay_many`
+/// 
+/// The caller has already run `authorize_payment`.
+fn pay_invoice(
+    env: &Env,
+    invoice_id: BytesN<32>,
+    payer: Address,
+    amount: i128,
+    expected_revision: Option<u32>,
+    cart_id: Option<BytesN<32>>,
+) -> Result<(), Error> {
+    // 1. Load invoice
+    let mut invoice = load_invoice(env, &invoice_id)?;
+    
+    // 2. Check status, the revision the payer saw (if pinned) and any
+    //    payer restriction
+    if !invoice.is_awaiting_payment() {
+        return Err(Error::InvoiceNotOpen);
+    }
+    if expected_revision.is_some_and(|revision| revision != invoice.revision) {
+        return Err(Error::RevisionMismatch);
+    }
+    if invoice.designated_payer.as_ref().is_some_and(|designated| *designated != payer) {
+        return Err(Error::PayerNotAllowed);
+    }
+    
+    // 3. Check expiry (a failed call cannot persist `Expired`; that
+    //    is left to `expire_invoices`)
+    let current_time = env.ledger().timestamp();
+    if invoice.status_at(current_time) == InvoiceStatus::Expired {
+        return Err(Error::InvoiceExpired);
+    }
+    
+    // 4. Validate the amount and that the token is still accepted
+    let outstanding = invoice.amount - invoice.amount_paid;
+    let valid_amount = match invoice.amount_mode {
+        AmountMode::Range(min, max) => amount >= min && amount <= max,
+        AmountMode::Fixed if invoice.allow_partial => amount > 0 && amount <= outstanding,
+        AmountMode::Fixed => amount == invoice.amount,
+    };
+    if !valid_amount {
+        return Err(Error::AmountMismatch);
+    }
+    if let AmountMode::Range(..) = invoice.amount_mode {
+        invoice.amount = amount;
+        invoice.breakdown = InvoiceBreakdown::flat(env, amount);
+    }
+    if !is_token_allowed(env, &invoice.token) {
+        return Err(Error::TokenNotAllowed);
+    }
+    
+    // 5. Work out the platform fee at the merchant's current rate
+    let (fee, collector, fee_source) = fees::platform_fee(
+        env,
+        &invoice.merchant,
+        &invoice.token,
+        amount,
+        invoice.amount_paid,
+        invoice.amount,
+    )?;
+    if fee > 0 {
+        invoice.fee_collector = collector;
+    }
+    
+    // 6. Transfer the invoice token; installments and escrowed payments
+    //    wait in the contract
+    let token_client = token::Client::new(env, &invoice.token);
+    let contract = env.current_contract_address();
+    
+    if invoice.allow_partial || invoice.release_delay.is_some() {
+        token_client.transfer(&payer, &contract, &amount);
+    } else {
+        pay_out(env, &token_client, &payer, &invoice, amount - fee)?;
+        collect_fee(env, &token_client, &payer, &invoice, fee);
+    }
+    
+    // 7. Create payment record, fixing how its fee is treated on refund
+    let payment = Payment {
+        invoice_id: invoice_id.clone(),
+        payer: payer.clone(),
+        amount,
+        fee,
+        net: amount - fee,
+        fee_refund: fee_refund_mode(env),
+        timestamp: current_time,
+    };
+    
+    env.storage()
+        .persistent()
+        .set(&DataKey::Payment(invoice_id.clone(), invoice.payment_count), &payment);
+    
+    // 8. Update invoice status
+    invoice.amount_paid += amount;
+    invoice.fee_total += fee;
+    invoice.payment_count += 1;
+    invoice.payer = Some(payer.clone());
+    invoice.updated_at = current_time;
+    
+    if invoice.amount_paid < invoice.amount {
+        invoice.status = InvoiceStatus::PartiallyPaid;
+        save_invoice(env, &invoice);
+        
+        env.events().publish(
+            (symbol_short!("part_paid"), invoice.merchant.clone()),
+            (invoice_id, payer, amount, invoice.amount_paid),
+        );
+        return Ok(());
+    }
+    
+    // 9. Hold escrowed funds until `release`, or pass them on
+    if let Some(delay) = invoice.release_delay {
+        let escrow = Escrow {
+            invoice_id: invoice_id.clone(),
+            payer: payer.clone(),
+            amount: invoice.amount_paid,
+            fee: invoice.fee_total,
+            funded_at: current_time,
+            release_at: current_time.saturating_add(delay),
+        };
+        env.storage()
+            .persistent()
+            .set(&DataKey::Escrow(invoice_id.clone()), &escrow);
+        invoice.status = InvoiceStatus::Escrowed;
+        save_invoice(env, &invoice);
+        
+        env.events().publish(
+            (symbol_short!("escrowed"), invoice.merchant.clone()),
+            (invoice_id.clone(), escrow.amount, escrow.release_at),
+        );
+    } else {
+        if invoice.allow_partial {
+            let net_total = invoice.amount_paid - invoice.fee_total;
+            pay_out(env, &token_client, &contract, &invoice, net_total)?;
+            collect_fee(env, &token_client, &contract, &invoice, invoice.fee_total);
+        }
+        invoice.status = InvoiceStatus::Paid;
+        invoice.settled_at = Some(current_time);
+        save_invoice(env, &invoice);
+        fees::record_volume(env, &invoice.merchant, &invoice.token, invoice.amount);
+    }
+    
+    // 10. Emit event, with the fee rate of the settling payment
+    env.events().publish(
+        (symbol_short!("paid"), invoice.merchant.clone()),
+        (invoice_id, payer, invoice.amount, invoice.metadata, cart_id, fee_source),
+    );
+    
+    Ok(())
+}
+
+/// Helper to count the ledger entries settling a cart could write
+/// 
+/// Each item adds its invoice and payment records (and escrow record).
+/// Token balances and merchant volumes are counted once however many items
+/// touch them, and the payer's authorization nonce once for the cart. An
+/// item that settles its invoice counts every recipient and the fee
+/// collector, so the total is an upper bound.
+fn cart_writes(env: &Env, payer: &Address, items: &Vec<(BytesN<32>, i128)>) -> Result<u32, Error> {
+    let collector = fee_config(env).map(|fee| fee.collector);
+    let contract = env.current_contract_address();
+    let mut balances: Map<(Address, Address), bool> = Map::new(env);
+    let mut volumes: Map<(Address, Address), bool> = Map::new(env);
+    let mut records = 1; // the payer's nonce
+    
+    for (invoice_id, amount) in items.iter() {
+        let invoice = load_invoice(env, &invoice_id)?;
+        let token = invoice.token.clone();
+        records += 2;
+        balances.set((token.clone(), payer.clone()), true);
+        if invoice.allow_partial || invoice.release_delay.is_some() {
+            balances.set((token.clone(), contract.clone()), true);
+        }
+        if invoice.release_delay.is_some() {
+            records += 1;
+            continue;
+        }
+        if invoice.allow_partial && invoice.amount_paid.saturating_add(amount) < invoice.amount {
+            continue;
+        }
+        
+        if invoice.splits.is_empty() {
+            balances.set((token.clone(), invoice.merchant.clone()), true);
+        }
+        for split in invoice.splits.iter() {
+            balances.set((token.clone(), split.recipient), true);
+        }
+        if let Some(collector) = &collector {
+            balances.set((token.clone(), collector.clone()), true);
+        }
+        volumes.set((invoice.merchant.clone(), token), true);
+    }
+    
+    Ok(records + balances.len() + volumes.len())
+}
+
+/// Helper to send a settled amount to the merchant, or across the
+/// invoice's split table
+fn pay_out(
+    env: &Env,
+    token_client: &token::Client,
+    from: &Address,
+    invoice: &Invoice,
+    amount: i128,
+) -> Result<(), Error> {
+    if invoice.splits.is_empty() {
+        token_client.transfer(from, &invoice.merchant, &amount);
+        return Ok(());
+    }
+    
+    let shares = pricing::split_amount(env, &invoice.splits, amount)?;
+    for (split, share) in invoice.splits.iter().zip(shares.iter()) {
+        if share > 0 {
+            token_client.transfer(from, &split.recipient, &share);
+        }
+        
+        env.events().publish(
+            (symbol_short!("split"), split.recipient),
+            (invoice.id.clone(), share),
+        );
+    }
+    Ok(())
+}
+
+/// Helper to send a platform fee to the invoice's fee collector
+fn collect_fee(
+    env: &Env,
+    token_client: &token::Client,
+    from: &Address,
+    invoice: &Invoice,
+    fee: i128,
+) {
+    let Some(collector) = &invoice.fee_collector else {
+        return;
+    };
+    if fee <= 0 {
+        return;
+    }
+    
+    token_client.transfer(from, collector, &fee);
+    
+    env.events().publish(
+        (symbol_short!("fee"), collector.clone()),
+        (invoice.id.clone(), fee),
+    );
+}
+
+/// Helper to pull every split recipient's share of a refund into the
+/// contract
+/// 
+/// `from..to` is the part of the invoice's net amount being refunded,
+/// counted across all its refunds. Each recipient returns the difference
+/// between its shares of `to` and of `from`, so a series of partial
+/// refunds claws back exactly what it was paid. Rounding can make a share
+/// of a small refund negative; that recipient is paid it once the others
+/// have returned theirs.
+/// 
+/// # Returns
+/// * The contract address, now holding `to - from`
+fn claw_back(
+    env: &Env,
+    token_client: &token::Client,
+    invoice: &Invoice,
+    from: i128,
+    to: i128,
+) -> Result<Address, Error> {
+    let contract = env.current_contract_address();
+    let before = pricing::split_amount(env, &invoice.splits, from)?;
+    let after = pricing::split_amount(env, &invoice.splits, to)?;
+    
+    let mut shares = Vec::new(env);
+    for (i, split) in invoice.splits.iter().enumerate() {
+        // The merchant has already authorized the refund itself
+        if split.recipient != invoice.merchant {
+            split.recipient.require_auth();
+        }
+        let share = after.get_unchecked(i as u32) - before.get_unchecked(i as u32);
+        if share > 0 {
+            token_client.transfer(&split.recipient, &contract, &share);
+        }
+        shares.push_back(share);
+        
+        env.events().publish(
+            (symbol_short!("clawback"), split.recipient),
+            (invoice.id.clone(), share),
+        );
+    }
+    
+    for (split, share) in invoice.splits.iter().zip(shares.iter()) {
+        if share < 0 {
+            token_client.transfer(&contract, &split.recipient, &-share);
+        }
+    }
+    Ok(contract)
+}
+
+/// Helper to pay out the funds of an escrow from the contract
+/// 
+/// `payer_amount` goes back to the payer. The rest is paid out to the
+/// merchant (or across the split table) less its proportional share of the
+/// escrowed fee, which goes to the collector, and counts towards the
+/// merchant's volume. The invoice's `fee_total` is updated to the fee
+/// actually collected, and `settled_at` if the merchant was paid; the
+/// caller saves the invoice.
+/// 
+/// # Returns
+/// * The net amount paid out to the merchant
+fn settle_escrow(
+    env: &Env,
+    invoice: &mut Invoice,
+    escrow: &Escrow,
+    payer_amount: i128,
+) -> Result<i128, Error> {
+    let token_client = token::Client::new(env, &invoice.token);
+    let contract = env.current_contract_address();
+    
+    if payer_amount > 0 {
+        return_to_payer(env, &token_client, &contract, &escrow.payer, &invoice.id, payer_amount);
+    }
+    
+    let merchant_amount = escrow.amount - payer_amount;
+    let fee = pricing::prorate_fee(escrow.fee, merchant_amount, escrow.amount)?;
+    let net = merchant_amount - fee;
+    if merchant_amount > 0 {
+        pay_out(env, &token_client, &contract, invoice, net)?;
+        collect_fee(env, &token_client, &contract, invoice, fee);
+        fees::record_volume(env, &invoice.merchant, &invoice.token, merchant_amount);
+        invoice.settled_at = Some(env.ledger().timestamp());
+    }
+    invoice.fee_total = fee;
+    
+    Ok(net)
+}
+
+/// Helper to load an invoice from storage
+fn load_invoice(env: &Env, invoice_id: &BytesN<32>) -> Result<Invoice, Error> {
+    env.storage()
+        .persistent()
+        .get(&DataKey::Invoice(invoice_id.clone()))
+        .ok_or(Error::InvoiceNotFound)
+}
+
+/// Helper to load the escrow of an invoice from storage
+fn load_escrow(env: &Env, invoice_id: &BytesN<32>) -> Result<Escrow, Error> {
+    env.storage()
+        .persistent()
+        .get(&DataKey::Escrow(invoice_id.clone()))
+        .ok_or(Error::EscrowNotHeld)
+}
+
+/// Helper to write an invoice back to storage
+fn save_invoice(env: &Env, invoice: &Invoice) {
+    env.storage()
+        .persistent()
+        .set(&DataKey::Invoice(invoice.id.clone()), invoice);
+}
+
+/// Helper to load every payment record of an invoice, oldest first
+fn load_payments(env: &Env, invoice: &Invoice) -> Result<Vec<Payment>, Error> {
+    let mut payments = Vec::new(env);
+    for index in 0..invoice.payment_count {
+        let payment: Payment = env.storage()
+            .persistent()
+            .get(&DataKey::Payment(invoice.id.clone(), index))
+            .ok_or(Error::PaymentNotFound)?;
+        payments.push_back(payment);
+    }
+    Ok(payments)
+}
+
+/// Helper behind `refund` and `approve_refund`
+/// 
+/// Checks the invoice can be refunded by `amount`, transfers it back to the
+/// payers and stores the refund record. The caller has already checked the
+/// merchant's authorization; the fee collector's is required here when part
+/// of a fee is returned.
+/// 
+/// # Returns
+/// * Index of the new refund record
+fn issue_refund(
+    env: &Env,
+    mut invoice: Invoice,
+    amount: i128,
+    reason: u32,
+) -> Result<u32, Error> {
+    // 1. Check status (must be Paid, Escrowed, Released or
+    //    PartiallyRefunded)
+    let escrowed = invoice.status == InvoiceStatus::Escrowed;
+    if invoice.status != InvoiceStatus::Paid
+        && invoice.status != InvoiceStatus::Released
+        && invoice.status != InvoiceStatus::PartiallyRefunded
+        && !escrowed
+    {
+        return Err(Error::InvoiceNotPaid);
+    }
+    
+    // 2. Validate the amount against what remains refundable, under the
+    //    fee setting each payment was made with (escrowed funds are only
+    //    refunded whole)
+    let payments = load_payments(env, &invoice)?;
+    let refundable = refundable_total(&payments, escrowed);
+    let remaining = refundable - invoice.refunded_total;
+    if amount <= 0 || amount > remaining || (escrowed && amount != remaining) {
+        return Err(Error::AmountMismatch);
+    }
+    
+    // 3. Work out what each payer gets back, and which part of it is
+    //    a returned fee
+    let chunks = refund_chunks(env, &payments, invoice.refunded_total, amount, escrowed)?;
+    let mut net_total: i128 = 0;
+    let mut fee_total: i128 = 0;
+    for (_, net, fee) in chunks.iter() {
+        net_total += net;
+        fee_total += fee;
+    }
+    
+    // 4. Transfer the net parts back, from the merchant or, for split
+    //    invoices, via the contract after clawing back every
+    //    recipient's share (each recipient must authorize); returned
+    //    fees come from the collector, and escrowed payments are
+    //    returned whole from the contract
+    let token_client = token::Client::new(env, &invoice.token);
+    let source = if escrowed {
+        env.current_contract_address()
+    } else if invoice.splits.is_empty() {
+        invoice.merchant.clone()
+    } else {
+        let net_refunded = invoice.refunded_total - invoice.fees_refunded;
+        claw_back(env, &token_client, &invoice, net_refunded, net_refunded + net_total)?
+    };
+    let fee_collector = invoice.fee_collector.clone().filter(|_| fee_total > 0);
+    if let Some(collector) = &fee_collector {
+        if *collector != invoice.merchant {
+            collector.require_auth();
+        }
+    }
+    
+    for (payer, net, fee) in chunks.iter() {
+        if net > 0 {
+            return_to_payer(env, &token_client, &source, &payer, &invoice.id, net);
+        }
+        if let Some(collector) = &fee_collector {
+            if fee > 0 {
+                return_to_payer(env, &token_client, collector, &payer, &invoice.id, fee);
+            }
+        }
+        
+        env.events().publish(
+            (symbol_short!("refunded"), invoice.merchant.clone()),
+            (invoice.id.clone(), payer, net + fee),
+        );
+    }
+    
+    // 5. Store the refund record
+    let index = invoice.refund_count;
+    let refund = Refund {
+        invoice_id: invoice.id.clone(),
+        amount,
+        fee: fee_total,
+        reason,
+        timestamp: env.ledger().timestamp(),
+    };
+    env.storage()
+        .persistent()
+        .set(&DataKey::Refund(invoice.id.clone(), index), &refund);
+    
+    // 6. Take the refund back out of the merchant's volume, so refunded
+    //    payments cannot lift it to a cheaper fee tier
+    if let Some(settled_at) = invoice.settled_at {
+        fees::deduct_volume(env, &invoice.merchant, &invoice.token, amount, settled_at);
+    }
+    
+    // 7. Update invoice totals and status
+    invoice.refunded_total += amount;
+    invoice.fees_refunded += fee_total;
+    invoice.refund_count += 1;
+    invoice.status = if invoice.refunded_total == refundable {
+        InvoiceStatus::Refunded
+    } else {
+        InvoiceStatus::PartiallyRefunded
+    };
+    invoice.updated_at = refund.timestamp;
+    save_invoice(env, &invoice);
+    
+    Ok(index)
+}
+
+/// Helper to load a refund request from storage
+fn load_refund_request(
+    env: &Env,
+    invoice_id: &BytesN<32>,
+    index: u32,
+) -> Result<RefundRequest, Error> {
+    env.storage()
+        .persistent()
+        .get(&DataKey::RefundRequest(invoice_id.clone(), index))
+        .ok_or(Error::RequestNotFound)
+}
+
+/// Helper to read a merchant's refund request window
+fn refund_window(env: &Env, merchant: &Address) -> u64 {
+    env.storage()
+        .persistent()
+        .get(&DataKey::RefundWindow(merchant.clone()))
+        .unwrap_or(DEFAULT_REFUND_WINDOW_SECS)
+}
+
+/// Helper to sum what can be refunded of an invoice's payments in total
+fn refundable_total(payments: &Vec<Payment>, escrowed: bool) -> i128 {
+    payments.iter().fold(0, |total, payment| {
+        total + if escrowed { payment.amount } else { payment.refundable() }
+    })
+}
+
+/// Helper to spread a refund over an invoice's payments
+/// 
+/// Each payment can return up to `Payment::refundable`, or its gross
+/// amount while the funds are escrowed. The refund covers `amount` of that
+/// capacity, oldest payment first, starting after the `refunded` already
+/// returned. For a payment whose fee is returned, the fee is returned in
+/// proportion, rounded so that refunding the whole payment returns exactly
+/// its fee.
+/// 
+/// # Returns
+/// * (payer, net part, fee part) for every payment the refund touches
+fn refund_chunks(
+    env: &Env,
+    payments: &Vec<Payment>,
+    refunded: i128,
+    amount: i128,
+    escrowed: bool,
+) -> Result<Vec<(Address, i128, i128)>, Error> {
+    let mut chunks = Vec::new(env);
+    let end = refunded + amount;
+    let mut offset: i128 = 0;
+    
+    for payment in payments.iter() {
+        let capacity = if escrowed { payment.amount } else { payment.refundable() };
+        let from = refunded.max(offset) - offset;
+        let to = end.min(offset + capacity) - offset;
+        offset += capacity;
+        if from >= to {
+            continue;
+        }
+        
+        let fee = if !escrowed && payment.fee_refund == FeeRefund::Returned {
+            pricing::prorate_fee(payment.fee, to, payment.amount)?
+                - pricing::prorate_fee(payment.fee, from, payment.amount)?
+        } else {
+            0
+        };
+        chunks.push_back((payment.payer, to - from - fee, fee));
+    }
+    Ok(chunks)
+}
+
+/// Return installments held by the contract to their payers
+fn refund_installments(env: &Env, invoice: &Invoice) {
+    let token_client = token::Client::new(env, &invoice.token);
+    let contract = env.current_contract_address();
+    
+    for index in 0..invoice.payment_count {
+        let key = DataKey::Payment(invoice.id.clone(), index);
+        let Some(payment) = env.storage().persistent().get::<_, Payment>(&key) else {
+            continue;
+        };
+        return_to_payer(env, &token_client, &contract, &payment.payer, &invoice.id, payment.amount);
+        
+        env.events().publish(
+            (symbol_short!("auto_rfnd"), payment.payer),
+            (invoice.id.clone(), payment.amount),
+        );
+    }
+}
+
+/// Helper to send money back to a payer
+/// 
+/// If the payer cannot receive the token, because their trustline was
+/// removed or their balance is frozen, the amount is moved into the
+/// contract instead and credited to the payer's claimable balance; the
+/// sender then authorizes a transfer to the contract rather than to the
+/// payer. Any other failure, including one on the sender's side, aborts
+/// with the token's error, so no balance is credited without the funds.
+fn return_to_payer(
+    env: &Env,
+    token_client: &token::Client,
+    from: &Address,
+    payer: &Address,
+    invoice_id: &BytesN<32>,
+    amount: i128,
+) {
+    // The inner conversion into `soroban_sdk::Error` cannot fail
+    let Err(Ok(error)) = token_client.try_transfer(from, payer, &amount) else {
+        return;
+    };
+    if !recipient_rejected(env, token_client, from, error) {
+        env.panic_with_error(error);
+    }
+    
+    let contract = env.current_contract_address();
+    if *from != contract {
+        token_client.transfer(from, &contract, &amount);
+    }
+    
+    let token = token_client.address.clone();
+    let key = DataKey::Claimable(payer.clone(), token.clone());
+    let balance: i128 = env.storage().persistent().get(&key).unwrap_or(0);
+    env.storage().persistent().set(&key, &(balance + amount));
+    let total_key = DataKey::ClaimableTotal(token.clone());
+    let total: i128 = env.storage().persistent().get(&total_key).unwrap_or(0);
+    env.storage().persistent().set(&total_key, &(total + amount));
+    
+    env.events().publish(
+        (symbol_short!("credited"), payer.clone()),
+        (invoice_id.clone(), token, amount),
+    );
+}
+
+/// Helper to tell whether a failed transfer was refused on the recipient's
+/// side
+/// 
+/// Only Stellar asset contract errors are recognized. A deauthorized
+/// balance is reported the same way for either side, so the sender's
+/// balance must still be authorized.
+fn recipient_rejected(
+    env: &Env,
+    token_client: &token::Client,
+    from: &Address,
+    error: soroban_sdk::Error,
+) -> bool {
+    if !SAC_RECIPIENT_ERRORS
+        .iter()
+        .any(|code| error == soroban_sdk::Error::from_contract_error(*code))
+    {
+        return false;
+    }
+    
+    let asset = token::StellarAssetClient::new(env, &token_client.address);
+    matches!(asset.try_authorized(from), Ok(Ok(true)))
+}
+
+/// Helper to load the contract configuration from storage
+/// 
+/// A configuration still in the version 1 layout is reported as
+/// `Error::MigrationRequired` until `migrate` rewrites it.
+fn load_config(env: &Env) -> Result<Config, Error> {
+    match migration::read_config(env)? {
+        (config, false) => Ok(config),
+        (_, true) => Err(Error::MigrationRequired),
+    }
+}
+
+/// Helper to read the platform fee settings
+fn fee_config(env: &Env) -> Option<FeeConfig> {
+    env.storage().instance().get(&DataKey::FeeConfig)
+}
+
+/// Helper to read whether refunds of a payment made now return its fee
+fn fee_refund_mode(env: &Env) -> FeeRefund {
+    fee_config(env).map_or(FeeRefund::Returned, |fee| fee.refund)
+}
+
+/// Helper to read the storage layout version
+fn schema_version(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::SchemaVersion)
+        .unwrap_or(1)
+}
+
+/// Helper to read the paused flag of an operation class
+fn is_paused(env: &Env, operation: Operation) -> bool {
+    env.storage()
+        .instance()
+        .get(&DataKey::Paused(operation))
+        .unwrap_or(false)
+}
+
+/// What a new invoice asks the payer for
+enum Pricing {
+    /// A fixed total, with the breakdown behind it
+    Fixed(InvoiceBreakdown),
+    /// Any amount between the two bounds (inclusive)
+    Range(i128, i128),
+}
+
+/// Helper shared by the invoice creation entrypoints
+/// 
+/// Validates the request against the merchant's policy and stores a new
+/// open invoice under `invoice_id`. The caller has already checked the
+/// merchant's authorization.
+fn open_invoice(
+    env: &Env,
+    merchant: Address,
+    invoice_id: BytesN<32>,
+    token: Address,
+    pricing: Pricing,
+    expiry: u64,
+    options: Option<InvoiceOptions>,
+) -> Result<BytesN<32>, Error> {
+
+    // 1. Check contract state
+    let policy = merchant_policy(env, &merchant)?;
+    require_current_schema(env)?;
+    require_not_paused(env, Operation::Invoicing)?;
+    
+    // 2. Validate token and amount
+    if !is_token_allowed(env, &token) {
+        return Err(Error::TokenNotAllowed);
+    }
+    
+    let in_policy = |amount: i128| {
+        amount > 0 && amount >= policy.min_amount && amount <= policy.max_amount
+    };
+    let (amount, amount_mode, breakdown) = match pricing {
+        Pricing::Fixed(breakdown) => (breakdown.total, AmountMode::Fixed, breakdown),
+        Pricing::Range(min, max) => {
+            if !in_policy(min) || !in_policy(max) || min > max {
+                return Err(Error::InvalidAmount);
+            }
+            (0, AmountMode::Range(min, max), InvoiceBreakdown::flat(env, 0))
+        }
+    };
+    if amount_mode == AmountMode::Fixed && !in_policy(amount) {
+        return Err(Error::InvalidAmount);
+    }
+    
+    // 3. Validate expiry against the merchant's policy window
+    let current_time = env.ledger().timestamp();
+    let min_expiry = current_time.saturating_add(policy.min_expiry_secs);
+    let max_expiry = current_time.saturating_add(policy.max_expiry_secs);
+    
+    if expiry < min_expiry || expiry > max_expiry {
+        return Err(Error::InvalidExpiry);
+    }
+    
+    let options = options.unwrap_or_else(|| InvoiceOptions::defaults(env));
+    options.metadata.validate()?;
+    pricing::validate_splits(&options.splits)?;
+    if options.allow_partial && amount_mode != AmountMode::Fixed {
+        return Err(Error::InvalidAmount);
+    }
+    if let Some(delay) = options.release_delay {
+        if delay == 0 || delay > MAX_RELEASE_DELAY_SECS || options.allow_partial {
+            return Err(Error::InvalidEscrow);
+        }
+    }
+    
+    // 4. Refuse to overwrite an existing invoice
+    let key = DataKey::Invoice(invoice_id.clone());
+    if env.storage().persistent().has(&key) {
+        return Err(Error::DuplicateInvoice);
+    }
+    
+    // 5. Create invoice
+    let invoice = Invoice {
+        id: invoice_id.clone(),
+        merchant: merchant.clone(),
+        token: token.clone(),
+        amount,
+        expiry,
+        status: InvoiceStatus::Open,
+        created_at: current_time,
+        updated_at: current_time,
+        payer: None,
+        metadata: options.metadata.clone(),
+        breakdown,
+        allow_partial: options.allow_partial,
+        amount_paid: 0,
+        payment_count: 0,
+        amount_mode,
+        revision: 0,
+        designated_payer: options.designated_payer.clone(),
+        splits: options.splits.clone(),
+        fee_total: 0,
+        fee_collector: None,
+        settled_at: None,
+        release_delay: options.release_delay,
+        refunded_total: 0,
+        fees_refunded: 0,
+        refund_count: 0,
+        request_count: 0,
+    };
+    
+    // 6. Store in persistent storage
+    env.storage().persistent().set(&key, &invoice);
+    
+    // 7. Emit event
+    env.events().publish(
+        (symbol_short!("created"), merchant),
+        (invoice_id.clone(), token, amount, expiry, options.metadata, options.designated_payer),
+    );
+    
+    Ok(invoice_id)
+}
+
+/// Helper to resolve the effective checkout policy for a merchant
+fn merchant_policy(env: &Env, merchant: &Address) -> Result<CheckoutPolicy, Error> {
+    let config = load_config(env)?;
+    Ok(env
+        .storage()
+        .persistent()
+        .get(&DataKey::MerchantPolicy(merchant.clone()))
+        .unwrap_or(config.default_policy))
+}
+
+/// Helper to check the token allowlist
+fn is_token_allowed(env: &Env, token: &Address) -> bool {
+    env.storage()
+        .persistent()
+        .get(&DataKey::AllowedToken(token.clone()))
+        .unwrap_or(false)
+}
+
+/// Helper to refuse calls in a paused operation class
+fn require_not_paused(env: &Env, operation: Operation) -> Result<(), Error> {
+    if is_paused(env, operation) {
+        return Err(Error::OperationPaused);
+    }
+    Ok(())
+}
+
+/// Helper shared by `pause` and `unpause`
+fn set_paused(env: &Env, caller: Address, operation: Operation, paused: bool) -> Result<(), Error> {
+    access::require_role(env, &caller, Role::Operator)?;
+    
+    env.storage().instance().set(&DataKey::Paused(operation), &paused);
+    
+    let topic = if paused { symbol_short!("paused") } else { symbol_short!("unpaused") };
+    env.events().publish((topic, caller), operation);
+    
+    Ok(())
+}
+
+/// Helper to refuse state changes while a migration is outstanding
+fn require_current_schema(env: &Env) -> Result<(), Error> {
+    if schema_version(env) < SCHEMA_VERSION {
+        return Err(Error::MigrationRequired);
+    }
+    Ok(())
 }
 
 #[cfg(test)]
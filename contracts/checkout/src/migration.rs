@@ -0,0 +1,188 @@
+use soroban_sdk::{contracttype, Address, BytesN, Env, Map, Symbol, TryFromVal, Val, Vec};
+
+use crate::load_config;
+use crate::types::{
+    AmountMode, CheckoutPolicy, Config, DataKey, Error, FeeRefund, Invoice, InvoiceBreakdown,
+    InvoiceMetadata, InvoiceStatus, Payment,
+};
+
+/// Storage keys of schema version 1 whose layout has since changed
+#[derive(Clone)]
+#[contracttype]
+pub enum DataKeyV1 {
+    /// Maps invoice_id -> Payment (one payment per invoice)
+    Payment(BytesN<32>),
+}
+
+/// Config layout used by schema version 1, with flat invoice limits
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct ConfigV1 {
+    pub admin: Address,
+    pub token: Address,
+    pub min_amount: i128,
+    pub max_amount: i128,
+    pub min_expiry_secs: u64,
+    pub max_expiry_secs: u64,
+}
+
+/// Payment layout used by schema version 1
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PaymentV1 {
+    pub invoice_id: BytesN<32>,
+    pub payer: Address,
+    pub amount: i128,
+    pub timestamp: u64,
+}
+
+/// Invoice layout used by schema version 1
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct InvoiceV1 {
+    pub id: BytesN<32>,
+    pub merchant: Address,
+    pub amount: i128,
+    pub expiry: u64,
+    pub status: InvoiceStatus,
+    pub created_at: u64,
+    pub payer: Option<Address>,
+}
+
+/// Reads the stored configuration in either the current or the version 1
+/// layout
+///
+/// # Returns
+/// The configuration in the current layout, and whether the stored entry
+/// is still in the version 1 layout and must be rewritten by `migrate`
+pub fn read_config(env: &Env) -> Result<(Config, bool), Error> {
+    let raw: Map<Symbol, Val> = env
+        .storage()
+        .instance()
+        .get(&DataKey::Config)
+        .ok_or(Error::NotInitialized)?;
+
+    // Version 2 replaced the flat limits with `default_policy`
+    if raw.contains_key(Symbol::new(env, "default_policy")) {
+        let config =
+            Config::try_from_val(env, &raw.to_val()).map_err(|_| Error::MigrationRequired)?;
+        return Ok((config, false));
+    }
+    let old = ConfigV1::try_from_val(env, &raw.to_val()).map_err(|_| Error::MigrationRequired)?;
+    Ok((config_from_v1(old), true))
+}
+
+/// Version 2 moved the flat amount and expiry limits into the default
+/// checkout policy.
+fn config_from_v1(old: ConfigV1) -> Config {
+    Config {
+        admin: old.admin,
+        token: old.token,
+        default_policy: CheckoutPolicy {
+            min_amount: old.min_amount,
+            max_amount: old.max_amount,
+            min_expiry_secs: old.min_expiry_secs,
+            max_expiry_secs: old.max_expiry_secs,
+        },
+    }
+}
+
+/// Rewrites a single invoice from `from_version` to the current layout
+///
+/// # Arguments
+/// * `env` - The Soroban environment
+/// * `from_version` - Schema version the stored entry was written with
+/// * `invoice_id` - The invoice to rewrite
+///
+/// # Returns
+/// `true` if an entry was rewritten, `false` if no invoice exists under the
+/// ID or it is not in the old layout (for example, already migrated)
+pub fn migrate_invoice(env: &Env, from_version: u32, invoice_id: &BytesN<32>) -> Result<bool, Error> {
+    let key = DataKey::Invoice(invoice_id.clone());
+    let Some(raw) = env.storage().persistent().get::<_, Map<Symbol, Val>>(&key) else {
+        return Ok(false);
+    };
+
+    let invoice = match from_version {
+        1 => {
+            // Version 1 entries have no `token` field; later ones always do
+            if raw.contains_key(Symbol::new(env, "token")) {
+                return Ok(false);
+            }
+            let Ok(old) = InvoiceV1::try_from_val(env, &raw.to_val()) else {
+                return Ok(false);
+            };
+            invoice_from_v1(env, old)?
+        }
+        _ => return Err(Error::SchemaUpToDate),
+    };
+
+    env.storage().persistent().set(&key, &invoice);
+    Ok(true)
+}
+
+/// Version 2 added `updated_at`, taken from the payment record when one
+/// exists, `token`, which for version 1 was always the configured token,
+/// empty `metadata`, a flat `breakdown`, installment, fee and refund
+/// tracking, and defaults for the remaining options, which settle directly
+/// rather than through escrow. Version 1 kept no merchant volume, so no
+/// settlement is recorded for refunds to deduct from. The single payment
+/// record moves to index 0 of the per-invoice payment list, with no fee
+/// deducted; a refunded invoice counts its payment as refunded, without a
+/// refund record.
+fn invoice_from_v1(env: &Env, old: InvoiceV1) -> Result<Invoice, Error> {
+    let config = load_config(env)?;
+    let old_payment_key = DataKeyV1::Payment(old.id.clone());
+    let payment: Option<PaymentV1> = env.storage().persistent().get(&old_payment_key);
+    
+    let mut updated_at = old.created_at;
+    let mut amount_paid = 0;
+    let mut payment_count = 0;
+    if let Some(payment) = payment {
+        updated_at = payment.timestamp;
+        amount_paid = payment.amount;
+        payment_count = 1;
+        let payment = Payment {
+            invoice_id: payment.invoice_id,
+            payer: payment.payer,
+            amount: payment.amount,
+            fee: 0,
+            net: payment.amount,
+            fee_refund: FeeRefund::Returned,
+            timestamp: payment.timestamp,
+        };
+        env.storage()
+            .persistent()
+            .set(&DataKey::Payment(old.id.clone(), 0), &payment);
+        env.storage().persistent().remove(&old_payment_key);
+    }
+
+    Ok(Invoice {
+        id: old.id,
+        merchant: old.merchant,
+        token: config.token,
+        amount: old.amount,
+        expiry: old.expiry,
+        status: old.status,
+        created_at: old.created_at,
+        updated_at,
+        payer: old.payer,
+        metadata: InvoiceMetadata::empty(),
+        breakdown: InvoiceBreakdown::flat(env, old.amount),
+        allow_partial: false,
+        amount_mode: AmountMode::Fixed,
+        revision: 0,
+        designated_payer: None,
+        splits: Vec::new(env),
+        amount_paid,
+        payment_count,
+        fee_total: 0,
+        fee_collector: None,
+        settled_at: None,
+        release_delay: None,
+        refunded_total: if old.status == InvoiceStatus::Refunded { amount_paid } else { 0 },
+        fees_refunded: 0,
+        refund_count: 0,
+        request_count: 0,
+    })
+}
@@ -0,0 +1,195 @@
+use soroban_sdk::{Env, Vec};
+
+use crate::types::{
+    Discount, Error, InvoiceBreakdown, LineItem, SplitShare, BPS_DENOMINATOR, MAX_LINE_ITEMS,
+    MAX_SKU_LEN, MAX_SPLITS,
+};
+
+/// Computes the total of an itemized invoice
+///
+/// Rounding is defined as follows:
+/// * A percentage discount is rounded down.
+/// * The discount is spread over the lines in proportion to their net
+///   amount, rounding each share down; the remainder is then added to the
+///   lines in item order, never taking a line below zero.
+/// * Tax is computed per line on the discounted net amount and rounded
+///   half up.
+///
+/// All arithmetic is checked and fails with `Error::ArithmeticOverflow`.
+///
+/// # Arguments
+/// * `env` - The Soroban environment
+/// * `items` - Between 1 and `MAX_LINE_ITEMS` lines
+/// * `discount` - Optional invoice-level discount
+///
+/// # Returns
+/// The breakdown, whose `total` becomes the invoice amount
+pub fn compute_breakdown(
+    env: &Env,
+    items: Vec<LineItem>,
+    discount: Option<Discount>,
+) -> Result<InvoiceBreakdown, Error> {
+    if items.is_empty() || items.len() > MAX_LINE_ITEMS {
+        return Err(Error::InvalidLineItem);
+    }
+
+    // 1. Validate lines and sum their net amounts
+    let mut nets: Vec<i128> = Vec::new(env);
+    let mut subtotal: i128 = 0;
+    for item in items.iter() {
+        if item.sku.is_empty()
+            || item.sku.len() > MAX_SKU_LEN
+            || item.quantity == 0
+            || item.unit_price <= 0
+            || i128::from(item.tax_bps) > BPS_DENOMINATOR
+        {
+            return Err(Error::InvalidLineItem);
+        }
+        let net = item
+            .unit_price
+            .checked_mul(i128::from(item.quantity))
+            .ok_or(Error::ArithmeticOverflow)?;
+        subtotal = subtotal.checked_add(net).ok_or(Error::ArithmeticOverflow)?;
+        nets.push_back(net);
+    }
+
+    // 2. Resolve the discount amount
+    let discount_total = match discount {
+        None => 0,
+        Some(Discount::Percent(bps)) => {
+            if i128::from(bps) > BPS_DENOMINATOR {
+                return Err(Error::InvalidDiscount);
+            }
+            mul_div_floor(subtotal, i128::from(bps), BPS_DENOMINATOR)?
+        }
+        Some(Discount::Fixed(amount)) => {
+            if amount < 0 || amount > subtotal {
+                return Err(Error::InvalidDiscount);
+            }
+            amount
+        }
+    };
+
+    // 3. Spread the discount over the lines
+    let mut shares: Vec<i128> = Vec::new(env);
+    let mut allocated: i128 = 0;
+    for net in nets.iter() {
+        let share = mul_div_floor(discount_total, net, subtotal)?;
+        allocated += share;
+        shares.push_back(share);
+    }
+    let mut remainder = discount_total - allocated;
+    for i in 0..shares.len() {
+        if remainder == 0 {
+            break;
+        }
+        let share = shares.get_unchecked(i);
+        let extra = remainder.min(nets.get_unchecked(i) - share);
+        shares.set(i, share + extra);
+        remainder -= extra;
+    }
+
+    // 4. Tax each discounted line
+    let mut tax: i128 = 0;
+    for (i, item) in items.iter().enumerate() {
+        let taxable = nets.get_unchecked(i as u32) - shares.get_unchecked(i as u32);
+        let line_tax = mul_div_half_up(taxable, i128::from(item.tax_bps), BPS_DENOMINATOR)?;
+        tax = tax.checked_add(line_tax).ok_or(Error::ArithmeticOverflow)?;
+    }
+
+    let total = (subtotal - discount_total)
+        .checked_add(tax)
+        .ok_or(Error::ArithmeticOverflow)?;
+
+    Ok(InvoiceBreakdown {
+        items,
+        subtotal,
+        discount: discount_total,
+        tax,
+        total,
+    })
+}
+
+/// Checks a revenue split table
+///
+/// An empty table means the merchant receives everything. Otherwise it
+/// holds at most `MAX_SPLITS` distinct recipients with non-zero shares that
+/// add up to exactly 100%.
+pub fn validate_splits(splits: &Vec<SplitShare>) -> Result<(), Error> {
+    if splits.len() > MAX_SPLITS {
+        return Err(Error::InvalidSplit);
+    }
+    
+    let mut total_bps: u32 = 0;
+    for (i, split) in splits.iter().enumerate() {
+        if split.bps == 0 || split.bps as i128 > BPS_DENOMINATOR {
+            return Err(Error::InvalidSplit);
+        }
+        if splits.iter().take(i).any(|earlier| earlier.recipient == split.recipient) {
+            return Err(Error::InvalidSplit);
+        }
+        total_bps += split.bps;
+    }
+    
+    if !splits.is_empty() && total_bps as i128 != BPS_DENOMINATOR {
+        return Err(Error::InvalidSplit);
+    }
+    Ok(())
+}
+
+/// Divides `amount` according to a split table
+///
+/// Each share is rounded down; the remainder goes to the first recipient,
+/// so the shares always add up to `amount`.
+///
+/// # Arguments
+/// * `env` - The Soroban environment
+/// * `splits` - A table accepted by `validate_splits`, not empty
+/// * `amount` - Amount to divide
+///
+/// # Returns
+/// One share per entry of `splits`, in the same order
+pub fn split_amount(env: &Env, splits: &Vec<SplitShare>, amount: i128) -> Result<Vec<i128>, Error> {
+    let mut shares = Vec::new(env);
+    let mut allocated: i128 = 0;
+    for split in splits.iter() {
+        let share = mul_div_floor(amount, split.bps as i128, BPS_DENOMINATOR)?;
+        allocated += share;
+        shares.push_back(share);
+    }
+    
+    if let Some(first) = shares.first() {
+        shares.set(0, first + (amount - allocated));
+    }
+    Ok(shares)
+}
+
+/// Computes the platform fee on a payment
+///
+/// The percentage of `bps` is rounded down, then raised to `min_fee`, and
+/// the result is capped at `amount` so the net amount is never negative.
+pub fn compute_fee(bps: u32, min_fee: i128, amount: i128) -> Result<i128, Error> {
+    let fee_amount = mul_div_floor(amount, bps as i128, BPS_DENOMINATOR)?;
+    Ok(fee_amount.max(min_fee).min(amount))
+}
+
+/// Part of `fee` attributable to `portion` of a payment of `amount`,
+/// rounded down
+pub fn prorate_fee(fee: i128, portion: i128, amount: i128) -> Result<i128, Error> {
+    mul_div_floor(fee, portion, amount)
+}
+
+/// `a * b / d` rounded down, for non-negative operands
+fn mul_div_floor(a: i128, b: i128, d: i128) -> Result<i128, Error> {
+    a.checked_mul(b)
+        .map(|product| product / d)
+        .ok_or(Error::ArithmeticOverflow)
+}
+
+/// `a * b / d` rounded half up, for non-negative operands
+fn mul_div_half_up(a: i128, b: i128, d: i128) -> Result<i128, Error> {
+    a.checked_mul(b)
+        .and_then(|product| product.checked_add(d / 2))
+        .map(|product| product / d)
+        .ok_or(Error::ArithmeticOverflow)
+}
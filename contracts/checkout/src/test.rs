@@ -1,35 +1,192 @@
 #[cfg(test)]
 mod tests {
-    use super::*;
-    use soroban_sdk::{Env, testutils::Address as _, token};
-    use types::Error;
+    use crate::{CheckoutContract, CheckoutContractClient};
+    use crate::migration::{ConfigV1, DataKeyV1, InvoiceV1, PaymentV1};
+    use crate::types::{
+        generate_invoice_id, AmountMode, ArbitrationConfig, CheckoutPolicy, DataKey, Discount,
+        DisputeStatus, Error, Escrow, FeeConfig, FeeRefund, FeeSource, FeeTier, Invoice,
+        InvoiceBreakdown, InvoiceMetadata, InvoiceOptions, InvoiceSpec, InvoiceStatus, LineItem,
+        Operation, Payment, RefundRequestStatus, Role, SplitShare, DEFAULT_REFUND_WINDOW_SECS,
+        MAX_BATCH_SIZE, MAX_FEE_TIERS,
+        MAX_REFUND_WINDOW_SECS, MAX_RELEASE_DELAY_SECS, MAX_SPLITS, SCHEMA_VERSION,
+        TX_WRITE_ENTRIES,
+    };
+    use soroban_sdk::{
+        testutils::{
+            Address as _, AuthorizedFunction, AuthorizedInvocation, Events as _, IssuerFlags,
+            Ledger as _, MockAuth, MockAuthInvoke,
+        },
+        contract, contractimpl, symbol_short, token, vec, xdr::ToXdr, Address, Bytes, BytesN, Env,
+        IntoVal, String, Symbol,
+    };
+
+    /// Data of the `paid` event
+    type PaidEvent = (BytesN<32>, Address, i128, InvoiceMetadata, Option<BytesN<32>>, FeeSource);
+
+    /// Minimal SEP-41 token with 6 decimals, standing in for EURC
+    #[contract]
+    pub struct SixDecimalToken;
+
+    #[contractimpl]
+    impl SixDecimalToken {
+        pub fn mint(env: Env, to: Address, amount: i128) {
+            let balance = Self::balance(env.clone(), to.clone());
+            env.storage().persistent().set(&to, &(balance + amount));
+        }
+
+        pub fn balance(env: Env, id: Address) -> i128 {
+            env.storage().persistent().get(&id).unwrap_or(0)
+        }
+
+        pub fn transfer(env: Env, from: Address, to: Address, amount: i128) {
+            from.require_auth();
+            let from_balance = Self::balance(env.clone(), from.clone());
+            assert!(from_balance >= amount, "insufficient balance");
+            env.storage().persistent().set(&from, &(from_balance - amount));
+            Self::mint(env, to, amount);
+        }
+
+        pub fn decimals(_env: Env) -> u32 {
+            6
+        }
+    }
+
+    /// Registers a Stellar asset contract to stand in for USDC
+    fn create_usdc(env: &Env) -> (Address, Address) {
+        let usdc_admin = Address::generate(env);
+        let usdc_id = env
+            .register_stellar_asset_contract_v2(usdc_admin.clone())
+            .address();
+        (usdc_id, usdc_admin)
+    }
+
+    /// Smallest WASM module the host accepts: just the environment meta
+    /// section declaring protocol 21
+    fn minimal_contract_wasm() -> [u8; 40] {
+        let mut wasm = [0u8; 40];
+        wasm[..8].copy_from_slice(b"\0asm\x01\0\0\0");
+        wasm[8..11].copy_from_slice(&[0x00, 0x1e, 0x11]);
+        wasm[11..28].copy_from_slice(b"contractenvmetav0");
+        wasm[28..40].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 21, 0, 0, 0, 0]);
+        wasm
+    }
+
+    /// Registers the checkout contract and initializes it with a fresh USDC token
+    fn setup(env: &Env) -> (CheckoutContractClient<'_>, Address, Address) {
+        let contract_id = env.register_contract(None, CheckoutContract);
+        let client = CheckoutContractClient::new(env, &contract_id);
+        let admin = Address::generate(env);
+        let (usdc_id, _) = create_usdc(env);
+        client.initialize(&admin, &usdc_id);
+        (client, admin, usdc_id)
+    }
 
     #[test]
     fn test_initialize() {
         let env = Env::default();
         let contract_id = env.register_contract(None, CheckoutContract);
         let client = CheckoutContractClient::new(&env, &contract_id);
+        let admin = Address::generate(&env);
         let usdc_address = Address::generate(&env);
+        env.mock_all_auths();
         
         // Test initialization
-        client.initialize(&usdc_address);
+        client.initialize(&admin, &usdc_address);
+        
+        let config = client.get_config();
+        assert_eq!(config.admin, admin);
+        assert_eq!(config.token, usdc_address);
+        assert_eq!(config.default_policy.min_expiry_secs, 300);
+        assert_eq!(config.default_policy.max_expiry_secs, 3600);
+    }
+
+    #[test]
+    fn test_initialize_twice() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, _, usdc_id) = setup(&env);
+        
+        let attacker = Address::generate(&env);
+        let result = client.try_initialize(&attacker, &usdc_id);
+        assert_eq!(result, Err(Ok(Error::AlreadyInitialized)));
+        assert_ne!(client.get_config().admin, attacker);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_initialize_requires_admin_auth() {
+        let env = Env::default();
+        let contract_id = env.register_contract(None, CheckoutContract);
+        let client = CheckoutContractClient::new(&env, &contract_id);
+        
+        // No auths mocked, so admin.require_auth() must fail
+        client.initialize(&Address::generate(&env), &Address::generate(&env));
+    }
+
+    #[test]
+    fn test_not_initialized() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let contract_id = env.register_contract(None, CheckoutContract);
+        let client = CheckoutContractClient::new(&env, &contract_id);
         
-        // No need for assertions since initialize doesn't return anything
+        let expiry = env.ledger().timestamp() + 600;
+        let fake_id = BytesN::from_array(&env, &[0u8; 32]);
+        assert_eq!(client.try_get_config(), Err(Ok(Error::NotInitialized)));
+        assert_eq!(
+            client.try_create_invoice(&merchant, &Address::generate(&env), &10_000_000, &expiry, &None),
+            Err(Ok(Error::NotInitialized))
+        );
+        assert_eq!(
+            client.try_pay(&fake_id, &payer, &10_000_000),
+            Err(Ok(Error::NotInitialized))
+        );
+        assert_eq!(
+            client.try_refund(&fake_id, &merchant, &10_000_000, &0),
+            Err(Ok(Error::NotInitialized))
+        );
+        assert_eq!(
+            client.try_expire_invoices(&vec![&env, fake_id]),
+            Err(Ok(Error::NotInitialized))
+        );
     }
     
     #[test]
     fn test_invoice_creation() {
         let env = Env::default();
         let merchant = Address::generate(&env);
+        let contract_id = env.register_contract(None, CheckoutContract);
         
         let invoice = Invoice {
-            id: generate_invoice_id(&env, &merchant),
+            id: env.as_contract(&contract_id, || generate_invoice_id(&env, &merchant)),
             merchant: merchant.clone(),
+            token: Address::generate(&env),
             amount: 1000000000, // 100 USDC
             expiry: env.ledger().timestamp() + 600,
             status: InvoiceStatus::Open,
             created_at: env.ledger().timestamp(),
+            updated_at: env.ledger().timestamp(),
             payer: None,
+            metadata: InvoiceMetadata::empty(),
+            breakdown: InvoiceBreakdown::flat(&env, 1000000000),
+            allow_partial: false,
+            amount_paid: 0,
+            payment_count: 0,
+            amount_mode: AmountMode::Fixed,
+            revision: 0,
+            designated_payer: None,
+            splits: vec![&env],
+            fee_total: 0,
+            fee_collector: None,
+            settled_at: None,
+            release_delay: None,
+            refunded_total: 0,
+            fees_refunded: 0,
+            refund_count: 0,
+            request_count: 0,
         };
         
         assert_eq!(invoice.status, InvoiceStatus::Open);
@@ -44,6 +201,13 @@ mod tests {
         assert_eq!(InvoiceStatus::Paid as u32, 1);
         assert_eq!(InvoiceStatus::Refunded as u32, 2);
         assert_eq!(InvoiceStatus::Expired as u32, 3);
+        assert_eq!(InvoiceStatus::PartiallyPaid as u32, 4);
+        assert_eq!(InvoiceStatus::Cancelled as u32, 5);
+        assert_eq!(InvoiceStatus::Escrowed as u32, 6);
+        assert_eq!(InvoiceStatus::Released as u32, 7);
+        assert_eq!(InvoiceStatus::Disputed as u32, 8);
+        assert_eq!(InvoiceStatus::Resolved as u32, 9);
+        assert_eq!(InvoiceStatus::PartiallyRefunded as u32, 10);
     }
     
     #[test]
@@ -51,12 +215,16 @@ mod tests {
         let env = Env::default();
         let merchant = Address::generate(&env);
         let payer = Address::generate(&env);
-        let invoice_id = generate_invoice_id(&env, &merchant);
+        let contract_id = env.register_contract(None, CheckoutContract);
+        let invoice_id = env.as_contract(&contract_id, || generate_invoice_id(&env, &merchant));
         
         let payment = Payment {
             invoice_id: invoice_id.clone(),
             payer: payer.clone(),
             amount: 1000000000,
+            fee: 0,
+            net: 1000000000,
+            fee_refund: FeeRefund::Returned,
             timestamp: env.ledger().timestamp(),
         };
         
@@ -69,14 +237,15 @@ mod tests {
     fn test_generate_invoice_id() {
         let env = Env::default();
         let merchant = Address::generate(&env);
+        let contract_id = env.register_contract(None, CheckoutContract);
         
-        let id1 = generate_invoice_id(&env, &merchant);
+        let id1 = env.as_contract(&contract_id, || generate_invoice_id(&env, &merchant));
         // Increment ledger to ensure different timestamp/sequence
         env.ledger().with_mut(|l| {
-            l.timestamp = l.timestamp + 1;
-            l.sequence = l.sequence + 1;
+            l.timestamp += 1;
+            l.sequence_number += 1;
         });
-        let id2 = generate_invoice_id(&env, &merchant);
+        let id2 = env.as_contract(&contract_id, || generate_invoice_id(&env, &merchant));
         
         // Two IDs generated for the same merchant should be different
         assert_ne!(id1, id2);
@@ -88,20 +257,23 @@ mod tests {
         env.mock_all_auths();
         
         let merchant = Address::generate(&env);
-        let contract_id = env.register_contract(None, CheckoutContract);
-        let client = CheckoutContractClient::new(&env, &contract_id);
+        let (client, _, usdc_id) = setup(&env);
         
         let amount = 10_000_000; // 1 USDC
         let expiry = env.ledger().timestamp() + 600; // 10 min
         
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         
         // Verify the invoice ID is a valid 32 byte value
         assert_eq!(invoice_id.len(), 32);
 
-        // Check that an event was emitted
-        let events = env.events().all();
-        assert_eq!(events.len(), 1);
+        // Check that a `created` event was emitted by the contract
+        let (emitter, topics, data) = env.events().all().last().unwrap();
+        assert_eq!(emitter, client.address);
+        assert_eq!(topics, (symbol_short!("created"), merchant.clone()).into_val(&env));
+        let data: (BytesN<32>, Address, i128, u64, InvoiceMetadata, Option<Address>) =
+            data.into_val(&env);
+        assert_eq!(data, (invoice_id, usdc_id, amount, expiry, InvoiceMetadata::empty(), None));
     }
 
     #[test]
@@ -110,13 +282,12 @@ mod tests {
         env.mock_all_auths();
         
         let merchant = Address::generate(&env);
-        let contract_id = env.register_contract(None, CheckoutContract);
-        let client = CheckoutContractClient::new(&env, &contract_id);
+        let (client, _, usdc_id) = setup(&env);
         
         let amount = 0; // Invalid
         let expiry = env.ledger().timestamp() + 600;
         
-        let result = client.try_create_invoice(&merchant, &amount, &expiry);
+        let result = client.try_create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         assert_eq!(result, Err(Ok(Error::InvalidAmount)));
     }
 
@@ -126,13 +297,12 @@ mod tests {
         env.mock_all_auths();
         
         let merchant = Address::generate(&env);
-        let contract_id = env.register_contract(None, CheckoutContract);
-        let client = CheckoutContractClient::new(&env, &contract_id);
+        let (client, _, usdc_id) = setup(&env);
         
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 30; // Too soon (< 5 min)
         
-        let result = client.try_create_invoice(&merchant, &amount, &expiry);
+        let result = client.try_create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         assert_eq!(result, Err(Ok(Error::InvalidExpiry)));
     }
 
@@ -142,13 +312,12 @@ mod tests {
         env.mock_all_auths();
         
         let merchant = Address::generate(&env);
-        let contract_id = env.register_contract(None, CheckoutContract);
-        let client = CheckoutContractClient::new(&env, &contract_id);
+        let (client, _, usdc_id) = setup(&env);
         
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 7200; // Too far (> 60 min)
         
-        let result = client.try_create_invoice(&merchant, &amount, &expiry);
+        let result = client.try_create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         assert_eq!(result, Err(Ok(Error::InvalidExpiry)));
     }
     
@@ -165,24 +334,23 @@ mod tests {
         let client = CheckoutContractClient::new(&env, &contract_id);
         
         // Deploy and setup USDC token
-        let usdc_admin = Address::generate(&env);
-        let usdc_id = env.register_stellar_asset_contract(usdc_admin.clone());
+        let (usdc_id, _) = create_usdc(&env);
         let usdc_client = token::Client::new(&env, &usdc_id);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
         
         // Initialize checkout contract with USDC address
-        client.initialize(&usdc_id);
+        client.initialize(&Address::generate(&env), &usdc_id);
         
         // Mint USDC to payer
-        usdc_client.mint(&usdc_admin, &payer, &100_000_000); // 10 USDC
+        usdc_admin_client.mint(&payer, &100_000_000); // 10 USDC
         
         // Create invoice
         let amount = 10_000_000; // 1 USDC
         let expiry = env.ledger().timestamp() + 600;
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         
         // Pay invoice
-        let result = client.pay(&invoice_id, &payer, &amount);
-        assert!(result.is_ok());
+        client.pay(&invoice_id, &payer, &amount);
         
         // Verify balances
         assert_eq!(usdc_client.balance(&merchant), 10_000_000);
@@ -201,15 +369,14 @@ mod tests {
         let client = CheckoutContractClient::new(&env, &contract_id);
         
         // Deploy and setup USDC token
-        let usdc_admin = Address::generate(&env);
-        let usdc_id = env.register_stellar_asset_contract(usdc_admin.clone());
+        let (usdc_id, _) = create_usdc(&env);
         
         // Initialize checkout contract with USDC address
-        client.initialize(&usdc_id);
+        client.initialize(&Address::generate(&env), &usdc_id);
         
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 600;
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         
         // Try to pay wrong amount
         let wrong_amount = 5_000_000;
@@ -231,15 +398,14 @@ mod tests {
         let client = CheckoutContractClient::new(&env, &contract_id);
         
         // Deploy and setup USDC token
-        let usdc_admin = Address::generate(&env);
-        let usdc_id = env.register_stellar_asset_contract(usdc_admin.clone());
+        let (usdc_id, _) = create_usdc(&env);
         
         // Initialize checkout contract with USDC address
-        client.initialize(&usdc_id);
+        client.initialize(&Address::generate(&env), &usdc_id);
         
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 300; // 5 min
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         
         // Fast-forward time past expiry
         env.ledger().with_mut(|li| {
@@ -248,6 +414,10 @@ mod tests {
         
         let result = client.try_pay(&invoice_id, &payer, &amount);
         assert_eq!(result, Err(Ok(Error::InvoiceExpired)));
+        
+        // Reads report the lapse even though nothing was persisted
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Expired));
+        assert_eq!(client.get_invoice(&invoice_id).unwrap().status, InvoiceStatus::Expired);
     }
     
     #[test]
@@ -260,9 +430,8 @@ mod tests {
         let client = CheckoutContractClient::new(&env, &contract_id);
         
         // Deploy and setup USDC token
-        let usdc_admin = Address::generate(&env);
-        let usdc_id = env.register_stellar_asset_contract(usdc_admin.clone());
-        client.initialize(&usdc_id);
+        let (usdc_id, _) = create_usdc(&env);
+        client.initialize(&Address::generate(&env), &usdc_id);
         
         // Try to pay non-existent invoice
         let fake_invoice_id = BytesN::from_array(&env, &[0; 32]);
@@ -283,20 +452,19 @@ mod tests {
         let client = CheckoutContractClient::new(&env, &contract_id);
         
         // Deploy and setup USDC token
-        let usdc_admin = Address::generate(&env);
-        let usdc_id = env.register_stellar_asset_contract(usdc_admin.clone());
-        let usdc_client = token::Client::new(&env, &usdc_id);
+        let (usdc_id, _) = create_usdc(&env);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
         
         // Initialize checkout contract with USDC address
-        client.initialize(&usdc_id);
+        client.initialize(&Address::generate(&env), &usdc_id);
         
         // Mint USDC to payer (enough for two payments)
-        usdc_client.mint(&usdc_admin, &payer, &200_000_000); 
+        usdc_admin_client.mint(&payer, &200_000_000); 
         
         // Create invoice
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 600;
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         
         // Pay invoice first time
         client.pay(&invoice_id, &payer, &amount);
@@ -317,24 +485,23 @@ mod tests {
         let contract_id = env.register_contract(None, CheckoutContract);
         let client = CheckoutContractClient::new(&env, &contract_id);
         
-        let usdc_admin = Address::generate(&env);
-        let usdc_id = env.register_stellar_asset_contract(usdc_admin.clone());
+        let (usdc_id, _) = create_usdc(&env);
         let usdc_client = token::Client::new(&env, &usdc_id);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
         
-        client.initialize(&usdc_id);
+        client.initialize(&Address::generate(&env), &usdc_id);
         
         // Setup: Mint USDC to payer
-        usdc_client.mint(&usdc_admin, &payer, &100_000_000);
+        usdc_admin_client.mint(&payer, &100_000_000);
         
         // Create and pay invoice
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 600;
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         client.pay(&invoice_id, &payer, &amount);
         
         // Now merchant has the USDC, refund it
-        let result = client.refund(&invoice_id, &merchant, &amount);
-        assert!(result.is_ok());
+        client.refund(&invoice_id, &merchant, &amount, &0);
         
         // Verify balances restored
         assert_eq!(usdc_client.balance(&payer), 100_000_000);
@@ -352,17 +519,16 @@ mod tests {
         let client = CheckoutContractClient::new(&env, &contract_id);
         
         // Setup USDC token
-        let usdc_admin = Address::generate(&env);
-        let usdc_id = env.register_stellar_asset_contract(usdc_admin.clone());
-        client.initialize(&usdc_id);
+        let (usdc_id, _) = create_usdc(&env);
+        client.initialize(&Address::generate(&env), &usdc_id);
         
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 600;
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         
         // Try to refund unpaid invoice
-        let result = client.try_refund(&invoice_id, &merchant, &amount);
-        assert_eq!(result, Err(Ok(Error::InvoiceNotOpen)));
+        let result = client.try_refund(&invoice_id, &merchant, &amount, &0);
+        assert_eq!(result, Err(Ok(Error::InvoiceNotPaid)));
     }
 
     #[test]
@@ -377,20 +543,19 @@ mod tests {
         let contract_id = env.register_contract(None, CheckoutContract);
         let client = CheckoutContractClient::new(&env, &contract_id);
         
-        let usdc_admin = Address::generate(&env);
-        let usdc_id = env.register_stellar_asset_contract(usdc_admin.clone());
-        let usdc_client = token::Client::new(&env, &usdc_id);
+        let (usdc_id, _) = create_usdc(&env);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
         
-        client.initialize(&usdc_id);
-        usdc_client.mint(&usdc_admin, &payer, &100_000_000);
+        client.initialize(&Address::generate(&env), &usdc_id);
+        usdc_admin_client.mint(&payer, &100_000_000);
         
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 600;
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         client.pay(&invoice_id, &payer, &amount);
         
         // Attacker tries to refund
-        let result = client.try_refund(&invoice_id, &attacker, &amount);
+        let result = client.try_refund(&invoice_id, &attacker, &amount, &0);
         assert_eq!(result, Err(Ok(Error::Unauthorized)));
     }
 
@@ -400,12 +565,11 @@ mod tests {
         env.mock_all_auths();
         
         let merchant = Address::generate(&env);
-        let contract_id = env.register_contract(None, CheckoutContract);
-        let client = CheckoutContractClient::new(&env, &contract_id);
+        let (client, _, usdc_id) = setup(&env);
         
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 600;
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         
         // Query invoice
         let invoice = client.get_invoice(&invoice_id);
@@ -440,20 +604,19 @@ mod tests {
         let contract_id = env.register_contract(None, CheckoutContract);
         let client = CheckoutContractClient::new(&env, &contract_id);
         
-        let usdc_admin = Address::generate(&env);
-        let usdc_id = env.register_stellar_asset_contract(usdc_admin.clone());
-        let usdc_client = token::Client::new(&env, &usdc_id);
+        let (usdc_id, _) = create_usdc(&env);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
         
-        client.initialize(&usdc_id);
-        usdc_client.mint(&usdc_admin, &payer, &100_000_000);
+        client.initialize(&Address::generate(&env), &usdc_id);
+        usdc_admin_client.mint(&payer, &100_000_000);
         
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 600;
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         client.pay(&invoice_id, &payer, &amount);
         
         // Query payment
-        let payment = client.get_payment(&invoice_id);
+        let payment = client.get_payment(&invoice_id, &0);
         assert!(payment.is_some());
         
         let payment = payment.unwrap();
@@ -467,12 +630,11 @@ mod tests {
         env.mock_all_auths();
         
         let merchant = Address::generate(&env);
-        let contract_id = env.register_contract(None, CheckoutContract);
-        let client = CheckoutContractClient::new(&env, &contract_id);
+        let (client, _, usdc_id) = setup(&env);
         
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 600;
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         
         let status = client.get_invoice_status(&invoice_id);
         assert_eq!(status, Some(InvoiceStatus::Open));
@@ -489,17 +651,16 @@ mod tests {
         let contract_id = env.register_contract(None, CheckoutContract);
         let client = CheckoutContractClient::new(&env, &contract_id);
         
-        let usdc_admin = Address::generate(&env);
-        let usdc_id = env.register_stellar_asset_contract(usdc_admin.clone());
-        let usdc_client = token::Client::new(&env, &usdc_id);
+        let (usdc_id, _) = create_usdc(&env);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
         
-        client.initialize(&usdc_id);
-        usdc_client.mint(&usdc_admin, &payer, &100_000_000);
+        client.initialize(&Address::generate(&env), &usdc_id);
+        usdc_admin_client.mint(&payer, &100_000_000);
         
         // 1. Create invoice
         let amount = 10_000_000;
         let expiry = env.ledger().timestamp() + 600;
-        let invoice_id = client.create_invoice(&merchant, &amount, &expiry);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
         
         // Check initial status
         let status = client.get_invoice_status(&invoice_id);
@@ -513,10 +674,3448 @@ mod tests {
         assert_eq!(status, Some(InvoiceStatus::Paid));
         
         // 3. Refund invoice
-        client.refund(&invoice_id, &merchant, &amount);
+        client.refund(&invoice_id, &merchant, &amount, &0);
         
         // Check refunded status
         let status = client.get_invoice_status(&invoice_id);
         assert_eq!(status, Some(InvoiceStatus::Refunded));
     }
+
+    #[test]
+    fn test_upgrade() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, admin, _) = setup(&env);
+        
+        let wasm_hash = env
+            .deployer()
+            .upload_contract_wasm(minimal_contract_wasm().as_slice());
+        client.upgrade(&wasm_hash);
+        
+        let (_, topics, data) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("upgraded"), admin).into_val(&env));
+        let data: BytesN<32> = data.into_val(&env);
+        assert_eq!(data, wasm_hash);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_upgrade_requires_admin() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, _, _) = setup(&env);
+        
+        let wasm_hash = env
+            .deployer()
+            .upload_contract_wasm(minimal_contract_wasm().as_slice());
+        
+        // Drop the mocked auths so the admin check must fail
+        env.set_auths(&[]);
+        client.upgrade(&wasm_hash);
+    }
+
+    #[test]
+    fn test_migrate_up_to_date() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, _, _) = setup(&env);
+        
+        assert_eq!(client.get_schema_version(), SCHEMA_VERSION);
+        let result = client.try_migrate(&vec![&env], &true);
+        assert_eq!(result, Err(Ok(Error::SchemaUpToDate)));
+    }
+
+    #[test]
+    fn test_migrate_from_v1() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let admin = Address::generate(&env);
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (usdc_id, _) = create_usdc(&env);
+        let contract_id = env.register_contract(None, CheckoutContract);
+        let client = CheckoutContractClient::new(&env, &contract_id);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&payer, &100_000_000);
+        
+        let amount = 10_000_000;
+        let created_at = env.ledger().timestamp();
+        let expiry = created_at + 600;
+        let open_id = BytesN::from_array(&env, &[1; 32]);
+        let paid_id = BytesN::from_array(&env, &[2; 32]);
+        
+        // Write storage the way a version 1 contract left it: a flat
+        // `Config`, no token allowlist and one payment per invoice
+        env.as_contract(&contract_id, || {
+            let instance = env.storage().instance();
+            instance.set(&DataKey::Config, &ConfigV1 {
+                admin: admin.clone(),
+                token: usdc_id.clone(),
+                min_amount: 1,
+                max_amount: 500_000_000,
+                min_expiry_secs: 60,
+                max_expiry_secs: 7_200,
+            });
+            instance.set(&DataKey::SchemaVersion, &1u32);
+            
+            let storage = env.storage().persistent();
+            storage.set(&DataKey::Invoice(open_id.clone()), &InvoiceV1 {
+                id: open_id.clone(),
+                merchant: merchant.clone(),
+                amount,
+                expiry,
+                status: InvoiceStatus::Open,
+                created_at,
+                payer: None,
+            });
+            storage.set(&DataKey::Invoice(paid_id.clone()), &InvoiceV1 {
+                id: paid_id.clone(),
+                merchant: merchant.clone(),
+                amount,
+                expiry,
+                status: InvoiceStatus::Paid,
+                created_at,
+                payer: Some(payer.clone()),
+            });
+            storage.set(&DataKeyV1::Payment(paid_id.clone()), &PaymentV1 {
+                invoice_id: paid_id.clone(),
+                payer: payer.clone(),
+                amount,
+                timestamp: created_at + 60,
+            });
+        });
+        
+        // Upgrade to this build; the flat `Config` is still readable
+        let wasm_hash = env
+            .deployer()
+            .upload_contract_wasm(minimal_contract_wasm().as_slice());
+        client.upgrade(&wasm_hash);
+        env.register_contract(&contract_id, CheckoutContract);
+        assert_eq!(client.try_get_config(), Err(Ok(Error::MigrationRequired)));
+        assert!(!client.is_token_allowed(&usdc_id));
+        usdc_admin_client.mint(&merchant, &amount);
+        
+        // Payment entrypoints are blocked until the migration completes
+        assert_eq!(client.get_schema_version(), 1);
+        assert_eq!(
+            client.try_create_invoice(&merchant, &usdc_id, &amount, &expiry, &None),
+            Err(Ok(Error::MigrationRequired))
+        );
+        assert_eq!(client.migrate(&vec![&env, open_id.clone()], &false), 1);
+        
+        // The first batch rewrites the `Config` and allows its token
+        let config = client.get_config();
+        assert_eq!((config.admin, config.token.clone()), (admin, usdc_id.clone()));
+        assert_eq!(
+            config.default_policy,
+            CheckoutPolicy {
+                min_amount: 1,
+                max_amount: 500_000_000,
+                min_expiry_secs: 60,
+                max_expiry_secs: 7_200,
+            }
+        );
+        assert!(client.is_token_allowed(&usdc_id));
+        assert_eq!(
+            client.try_pay(&open_id, &payer, &amount),
+            Err(Ok(Error::MigrationRequired))
+        );
+        assert_eq!(
+            client.try_expire_invoices(&vec![&env, open_id.clone()]),
+            Err(Ok(Error::MigrationRequired))
+        );
+        let arbiter = Address::generate(&env);
+        client.grant_role(&Role::Arbiter, &arbiter);
+        assert_eq!(
+            client.try_vote_dispute(&open_id, &arbiter, &0),
+            Err(Ok(Error::MigrationRequired))
+        );
+
+        // Repeated and unknown IDs in a batch are skipped
+        let unknown_id = BytesN::from_array(&env, &[3; 32]);
+        assert_eq!(client.migrate(&vec![&env, open_id.clone(), unknown_id], &false), 0);
+        assert_eq!(client.migrate(&vec![&env, paid_id.clone()], &true), 1);
+        assert_eq!(client.get_schema_version(), SCHEMA_VERSION);
+        
+        // Both invoices survive with their state intact
+        let open = client.get_invoice(&open_id).unwrap();
+        assert_eq!(open.status, InvoiceStatus::Open);
+        assert_eq!(open.amount, amount);
+        assert_eq!(open.updated_at, created_at);
+        let paid = client.get_invoice(&paid_id).unwrap();
+        assert_eq!(paid.status, InvoiceStatus::Paid);
+        assert_eq!(paid.payer, Some(payer.clone()));
+        assert_eq!(paid.updated_at, created_at + 60);
+        assert_eq!(paid.token, usdc_id);
+        assert_eq!(paid.amount_paid, amount);
+        assert_eq!(paid.payment_count, 1);
+        assert_eq!(client.get_payment(&paid_id, &0).unwrap().payer, payer);
+        
+        // And remain usable afterwards
+        client.pay(&open_id, &payer, &amount);
+        client.refund(&paid_id, &merchant, &amount, &0);
+        assert_eq!(client.get_invoice_status(&open_id), Some(InvoiceStatus::Paid));
+        assert_eq!(client.get_invoice_status(&paid_id), Some(InvoiceStatus::Refunded));
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+        assert_eq!(usdc_client.balance(&merchant), amount);
+    }
+
+    #[test]
+    fn test_pause_each_operation() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let amount = 10_000_000;
+        let expiry = env.ledger().timestamp() + 600;
+        let paid_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
+        client.pay(&paid_id, &payer, &amount);
+        let open_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
+        
+        // Stop new invoices; payments and refunds keep working
+        client.pause(&admin, &Operation::Invoicing);
+        let (_, topics, data) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("paused"), admin.clone()).into_val(&env));
+        let data: Operation = data.into_val(&env);
+        assert_eq!(data, Operation::Invoicing);
+        assert!(client.is_paused(&Operation::Invoicing));
+        assert!(!client.is_paused(&Operation::Payments));
+        assert_eq!(
+            client.try_create_invoice(&merchant, &usdc_id, &amount, &expiry, &None),
+            Err(Ok(Error::OperationPaused))
+        );
+        client.refund(&paid_id, &merchant, &amount, &0);
+        
+        // Stop payments as well
+        client.pause(&admin, &Operation::Payments);
+        assert_eq!(
+            client.try_pay(&open_id, &payer, &amount),
+            Err(Ok(Error::OperationPaused))
+        );
+        
+        // Resume everything
+        client.unpause(&admin, &Operation::Invoicing);
+        client.unpause(&admin, &Operation::Payments);
+        let (_, topics, _) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("unpaused"), admin).into_val(&env));
+        client.pay(&open_id, &payer, &amount);
+        client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
+    }
+
+    #[test]
+    fn test_pause_refunds() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let amount = 10_000_000;
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
+        client.pay(&invoice_id, &payer, &amount);
+        
+        let partial_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &partial_options(&env));
+        client.pay(&partial_id, &payer, &4_000_000);
+        
+        client.pause(&admin, &Operation::Refunds);
+        assert_eq!(
+            client.try_refund(&invoice_id, &merchant, &amount, &0),
+            Err(Ok(Error::OperationPaused))
+        );
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Paid));
+        
+        // Held installments are not returned either: cancelling is refused
+        // and the expiry sweep skips the invoice
+        assert_eq!(
+            client.try_cancel_invoice(&partial_id, &merchant),
+            Err(Ok(Error::OperationPaused))
+        );
+        env.ledger().with_mut(|li| li.timestamp = expiry + 1);
+        assert_eq!(client.expire_invoices(&vec![&env, partial_id.clone()]), 0);
+        assert_eq!(token::Client::new(&env, &usdc_id).balance(&client.address), 4_000_000);
+        
+        client.unpause(&admin, &Operation::Refunds);
+        assert_eq!(client.expire_invoices(&vec![&env, partial_id]), 1);
+        assert_eq!(token::Client::new(&env, &usdc_id).balance(&payer), 90_000_000);
+    }
+
+    #[test]
+    fn test_pause_requires_operator() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, _, _) = setup(&env);
+        
+        let stranger = Address::generate(&env);
+        let result = client.try_pause(&stranger, &Operation::Payments);
+        assert_eq!(result, Err(Ok(Error::UnauthorizedAccess)));
+        assert!(!client.is_paused(&Operation::Payments));
+    }
+
+    #[test]
+    fn test_grant_and_revoke_operator() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, admin, _) = setup(&env);
+        
+        let operator = Address::generate(&env);
+        assert!(client.has_role(&Role::Operator, &admin));
+        assert!(!client.has_role(&Role::Operator, &operator));
+        
+        client.grant_role(&Role::Operator, &operator);
+        let (_, topics, data) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("granted"), operator.clone()).into_val(&env));
+        let data: Role = data.into_val(&env);
+        assert_eq!(data, Role::Operator);
+        assert!(client.has_role(&Role::Operator, &operator));
+        assert!(!client.has_role(&Role::FeeManager, &operator));
+        
+        // Operators can pause but hold no admin powers
+        client.pause(&operator, &Operation::Invoicing);
+        assert!(client.is_paused(&Operation::Invoicing));
+        client.unpause(&operator, &Operation::Invoicing);
+        assert!(!client.has_role(&Role::Admin, &operator));
+        
+        client.revoke_role(&Role::Operator, &operator);
+        let (_, topics, _) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("revoked"), operator.clone()).into_val(&env));
+        assert!(!client.has_role(&Role::Operator, &operator));
+        assert_eq!(
+            client.try_pause(&operator, &Operation::Invoicing),
+            Err(Ok(Error::UnauthorizedAccess))
+        );
+    }
+
+    #[test]
+    fn test_grant_admin_role_rejected() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, _, _) = setup(&env);
+        
+        let account = Address::generate(&env);
+        let result = client.try_grant_role(&Role::Admin, &account);
+        assert_eq!(result, Err(Ok(Error::InvalidRole)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_grant_role_requires_admin() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, _, _) = setup(&env);
+        
+        env.set_auths(&[]);
+        client.grant_role(&Role::Compliance, &Address::generate(&env));
+    }
+
+    #[test]
+    fn test_admin_handover() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, admin, _) = setup(&env);
+        
+        let typo = Address::generate(&env);
+        let new_admin = Address::generate(&env);
+        assert_eq!(client.try_accept_admin(), Err(Ok(Error::NoPendingAdmin)));
+        
+        // A wrong proposal changes nothing and can be replaced
+        client.propose_admin(&typo);
+        assert_eq!(client.get_config().admin, admin);
+        client.propose_admin(&new_admin);
+        assert_eq!(client.get_pending_admin(), Some(new_admin.clone()));
+        
+        client.accept_admin();
+        assert_eq!(env.auths()[0].0, new_admin);
+        let (_, topics, data) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("adm_xfer"), new_admin.clone()).into_val(&env));
+        let data: Address = data.into_val(&env);
+        assert_eq!(data, admin);
+        
+        assert_eq!(client.get_config().admin, new_admin);
+        assert_eq!(client.get_pending_admin(), None);
+        assert!(client.has_role(&Role::Admin, &new_admin));
+        assert!(!client.has_role(&Role::Admin, &admin));
+    }
+
+    #[test]
+    fn test_pay_in_two_tokens() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let eurc_id = env.register_contract(None, SixDecimalToken);
+        let eurc_client = SixDecimalTokenClient::new(&env, &eurc_id);
+        eurc_client.mint(&payer, &50_000_000);
+        assert_eq!(usdc_client.decimals(), 7);
+        assert_eq!(eurc_client.decimals(), 6);
+        
+        // EURC must be allowlisted before invoicing in it
+        let expiry = env.ledger().timestamp() + 600;
+        assert_eq!(
+            client.try_create_invoice(&merchant, &eurc_id, &5_000_000, &expiry, &None),
+            Err(Ok(Error::TokenNotAllowed))
+        );
+        client.add_token(&admin, &eurc_id);
+        assert!(client.is_token_allowed(&eurc_id));
+        
+        // 1 USDC (7 decimals) and 5 EURC (6 decimals)
+        let usdc_invoice = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        let eurc_invoice = client.create_invoice(&merchant, &eurc_id, &5_000_000, &expiry, &None);
+        assert_eq!(client.get_invoice(&eurc_invoice).unwrap().token, eurc_id);
+        
+        client.pay(&usdc_invoice, &payer, &10_000_000);
+        client.pay(&eurc_invoice, &payer, &5_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 10_000_000);
+        assert_eq!(usdc_client.balance(&payer), 90_000_000);
+        assert_eq!(eurc_client.balance(&merchant), 5_000_000);
+        assert_eq!(eurc_client.balance(&payer), 45_000_000);
+        
+        // Refunds move the invoice's own token back
+        client.refund(&eurc_invoice, &merchant, &5_000_000, &0);
+        assert_eq!(eurc_client.balance(&merchant), 0);
+        assert_eq!(eurc_client.balance(&payer), 50_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 10_000_000);
+    }
+
+    #[test]
+    fn test_remove_token() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let amount = 10_000_000;
+        let expiry = env.ledger().timestamp() + 600;
+        let paid_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
+        client.pay(&paid_id, &payer, &amount);
+        let open_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &None);
+        
+        client.remove_token(&admin, &usdc_id);
+        let (_, topics, _) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("tok_rm"), admin).into_val(&env));
+        assert!(!client.is_token_allowed(&usdc_id));
+        
+        // Delisted tokens can no longer be invoiced or paid, only refunded
+        assert_eq!(
+            client.try_create_invoice(&merchant, &usdc_id, &amount, &expiry, &None),
+            Err(Ok(Error::TokenNotAllowed))
+        );
+        assert_eq!(
+            client.try_pay(&open_id, &payer, &amount),
+            Err(Ok(Error::TokenNotAllowed))
+        );
+        client.refund(&paid_id, &merchant, &amount, &0);
+        assert_eq!(client.get_invoice_status(&paid_id), Some(InvoiceStatus::Refunded));
+    }
+
+    #[test]
+    fn test_merchant_policy_override() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let b2b = Address::generate(&env);
+        let kiosk = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let now = env.ledger().timestamp();
+        
+        // B2B invoices valid for up to 7 days, at least 100 USDC
+        let b2b_policy = CheckoutPolicy {
+            min_amount: 1_000_000_000,
+            max_amount: i128::MAX,
+            min_expiry_secs: 3600,
+            max_expiry_secs: 7 * 24 * 3600,
+        };
+        client.set_merchant_policy(&admin, &b2b, &b2b_policy);
+        assert_eq!(client.get_merchant_policy(&b2b), b2b_policy);
+        client.create_invoice(&b2b, &usdc_id, &1_000_000_000, &(now + 3 * 24 * 3600), &None);
+        assert_eq!(
+            client.try_create_invoice(&b2b, &usdc_id, &10_000_000, &(now + 24 * 3600), &None),
+            Err(Ok(Error::InvalidAmount))
+        );
+        assert_eq!(
+            client.try_create_invoice(&b2b, &usdc_id, &1_000_000_000, &(now + 8 * 24 * 3600), &None),
+            Err(Ok(Error::InvalidExpiry))
+        );
+        
+        // The bounds apply in each token's smallest unit: 100 EURC (6
+        // decimals) falls short of a minimum set with USDC (7) in mind
+        let eurc_id = env.register_contract(None, SixDecimalToken);
+        client.add_token(&admin, &eurc_id);
+        assert_eq!(
+            client.try_create_invoice(&b2b, &eurc_id, &100_000_000, &(now + 24 * 3600), &None),
+            Err(Ok(Error::InvalidAmount))
+        );
+        client.create_invoice(&b2b, &eurc_id, &1_000_000_000, &(now + 24 * 3600), &None);
+        
+        // Kiosk invoices valid for 1 to 2 minutes
+        client.set_merchant_policy(&admin, &kiosk, &CheckoutPolicy {
+            min_amount: 1,
+            max_amount: 100_000_000,
+            min_expiry_secs: 60,
+            max_expiry_secs: 120,
+        });
+        client.create_invoice(&kiosk, &usdc_id, &30_000_000, &(now + 120), &None);
+        assert_eq!(
+            client.try_create_invoice(&kiosk, &usdc_id, &30_000_000, &(now + 600), &None),
+            Err(Ok(Error::InvalidExpiry))
+        );
+        
+        // Clearing the override falls back to the default 5-60 minutes
+        client.clear_merchant_policy(&admin, &kiosk);
+        assert_eq!(
+            client.try_create_invoice(&kiosk, &usdc_id, &30_000_000, &(now + 120), &None),
+            Err(Ok(Error::InvalidExpiry))
+        );
+        client.create_invoice(&kiosk, &usdc_id, &30_000_000, &(now + 600), &None);
+    }
+
+    #[test]
+    fn test_compliance_role() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let compliance = Address::generate(&env);
+        let fee_manager = Address::generate(&env);
+        let merchant = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let (token_id, _) = create_usdc(&env);
+        client.grant_role(&Role::FeeManager, &fee_manager);
+        let policy = CheckoutPolicy {
+            min_amount: 1_000,
+            max_amount: 1_000_000,
+            min_expiry_secs: 60,
+            max_expiry_secs: 600,
+        };
+        
+        // Other roles cannot manage tokens or merchant policies
+        assert_eq!(client.try_add_token(&fee_manager, &token_id), Err(Ok(Error::UnauthorizedAccess)));
+        assert_eq!(client.try_remove_token(&fee_manager, &usdc_id), Err(Ok(Error::UnauthorizedAccess)));
+        assert_eq!(
+            client.try_set_merchant_policy(&fee_manager, &merchant, &policy),
+            Err(Ok(Error::UnauthorizedAccess))
+        );
+        assert_eq!(
+            client.try_clear_merchant_policy(&fee_manager, &merchant),
+            Err(Ok(Error::UnauthorizedAccess))
+        );
+        
+        // A compliance officer can
+        client.grant_role(&Role::Compliance, &compliance);
+        client.add_token(&compliance, &token_id);
+        let (_, topics, _) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("tok_add"), compliance.clone()).into_val(&env));
+        assert!(client.is_token_allowed(&token_id));
+        client.remove_token(&compliance, &usdc_id);
+        assert!(!client.is_token_allowed(&usdc_id));
+        
+        client.set_merchant_policy(&compliance, &merchant, &policy);
+        assert_eq!(client.get_merchant_policy(&merchant), policy);
+        client.clear_merchant_policy(&compliance, &merchant);
+        assert_eq!(client.get_merchant_policy(&merchant), client.get_config().default_policy);
+        
+        // As can the admin, who holds every role but `Arbiter`
+        client.add_token(&admin, &usdc_id);
+        assert!(client.is_token_allowed(&usdc_id));
+    }
+
+    #[test]
+    fn test_set_default_policy() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let now = env.ledger().timestamp();
+        
+        let policy = CheckoutPolicy {
+            min_amount: 5_000_000,
+            max_amount: 50_000_000,
+            min_expiry_secs: 600,
+            max_expiry_secs: 86_400,
+        };
+        client.set_default_policy(&policy);
+        let (_, topics, _) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("def_pol"), admin).into_val(&env));
+        assert_eq!(client.get_config().default_policy, policy);
+        assert_eq!(client.get_merchant_policy(&merchant), policy);
+        
+        client.create_invoice(&merchant, &usdc_id, &10_000_000, &(now + 7200), &None);
+        assert_eq!(
+            client.try_create_invoice(&merchant, &usdc_id, &1_000_000, &(now + 7200), &None),
+            Err(Ok(Error::InvalidAmount))
+        );
+        assert_eq!(
+            client.try_create_invoice(&merchant, &usdc_id, &60_000_000, &(now + 7200), &None),
+            Err(Ok(Error::InvalidAmount))
+        );
+        assert_eq!(
+            client.try_create_invoice(&merchant, &usdc_id, &10_000_000, &(now + 300), &None),
+            Err(Ok(Error::InvalidExpiry))
+        );
+    }
+
+    #[test]
+    fn test_invalid_policy() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let (client, admin, _) = setup(&env);
+        
+        let inverted_amounts = CheckoutPolicy {
+            min_amount: 100,
+            max_amount: 10,
+            min_expiry_secs: 60,
+            max_expiry_secs: 120,
+        };
+        let inverted_expiry = CheckoutPolicy {
+            min_amount: 1,
+            max_amount: 10,
+            min_expiry_secs: 600,
+            max_expiry_secs: 120,
+        };
+        assert_eq!(
+            client.try_set_merchant_policy(&admin, &merchant, &inverted_amounts),
+            Err(Ok(Error::InvalidPolicy))
+        );
+        assert_eq!(
+            client.try_set_default_policy(&inverted_expiry),
+            Err(Ok(Error::InvalidPolicy))
+        );
+    }
+
+    #[test]
+    fn test_expire_invoices() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let amount = 10_000_000;
+        let now = env.ledger().timestamp();
+        let lapsing_id = client.create_invoice(&merchant, &usdc_id, &amount, &(now + 300), &None);
+        let paid_id = client.create_invoice(&merchant, &usdc_id, &amount, &(now + 300), &None);
+        client.pay(&paid_id, &payer, &amount);
+        let valid_id = client.create_invoice(&merchant, &usdc_id, &amount, &(now + 3600), &None);
+        let unknown_id = BytesN::from_array(&env, &[7; 32]);
+        
+        env.ledger().with_mut(|li| {
+            li.timestamp = now + 301;
+        });
+        
+        // Anyone can sweep; only the lapsed open invoice changes
+        let ids = vec![&env, lapsing_id.clone(), paid_id.clone(), valid_id.clone(), unknown_id];
+        assert_eq!(client.expire_invoices(&ids), 1);
+        let (_, topics, data) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("expired"), merchant.clone()).into_val(&env));
+        let data: BytesN<32> = data.into_val(&env);
+        assert_eq!(data, lapsing_id);
+        
+        // The status is now persisted, not just derived
+        let stored: Invoice = env.as_contract(&client.address, || {
+            env.storage().persistent().get(&DataKey::Invoice(lapsing_id.clone())).unwrap()
+        });
+        assert_eq!(stored.status, InvoiceStatus::Expired);
+        assert_eq!(stored.updated_at, now + 301);
+        assert_eq!(client.get_invoice_status(&paid_id), Some(InvoiceStatus::Paid));
+        assert_eq!(client.get_invoice_status(&valid_id), Some(InvoiceStatus::Open));
+        
+        // Sweeping again is a no-op
+        assert_eq!(client.expire_invoices(&ids), 0);
+        assert_eq!(
+            client.try_pay(&lapsing_id, &payer, &amount),
+            Err(Ok(Error::InvoiceNotOpen))
+        );
+    }
+
+    #[test]
+    fn test_generated_ids_are_merchant_namespaced() {
+        let env = Env::default();
+        let merchant_a = Address::generate(&env);
+        let merchant_b = Address::generate(&env);
+        let contract_id = env.register_contract(None, CheckoutContract);
+        
+        // Same ledger, same counter value, different merchants
+        let (id_a, id_b) = env.as_contract(&contract_id, || {
+            (
+                generate_invoice_id(&env, &merchant_a),
+                generate_invoice_id(&env, &merchant_b),
+            )
+        });
+        assert_ne!(id_a, id_b);
+    }
+
+    #[test]
+    fn test_create_invoice_with_ref() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let other_merchant = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        
+        let amount = 10_000_000;
+        let expiry = env.ledger().timestamp() + 600;
+        let order_ref = Bytes::from_slice(&env, b"ORDER-2024-0042");
+        
+        // The ID is known before the invoice exists
+        let expected_id = client.derive_invoice_id(&merchant, &order_ref);
+        assert!(client.get_invoice(&expected_id).is_none());
+        
+        let invoice_id = client.create_invoice_with_ref(&merchant, &order_ref, &usdc_id, &amount, &expiry, &None);
+        assert_eq!(invoice_id, expected_id);
+        assert_eq!(client.get_invoice(&invoice_id).unwrap().amount, amount);
+        
+        // And matches the documented preimage
+        let mut preimage = client.address.clone().to_xdr(&env);
+        preimage.append(&merchant.clone().to_xdr(&env));
+        preimage.append(&order_ref);
+        let computed: BytesN<32> = env.crypto().sha256(&preimage).into();
+        assert_eq!(invoice_id, computed);
+        
+        // A retry of the same order does not create a second invoice
+        assert_eq!(
+            client.try_create_invoice_with_ref(&merchant, &order_ref, &usdc_id, &amount, &expiry, &None),
+            Err(Ok(Error::DuplicateInvoice))
+        );
+        
+        // The same reference is free for another merchant
+        let other_id = client.create_invoice_with_ref(&other_merchant, &order_ref, &usdc_id, &amount, &expiry, &None);
+        assert_ne!(other_id, invoice_id);
+    }
+
+    #[test]
+    fn test_create_invoice_with_invalid_ref() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let expiry = env.ledger().timestamp() + 600;
+        
+        let empty = Bytes::new(&env);
+        let too_long = Bytes::from_slice(&env, &[b'x'; 65]);
+        assert_eq!(
+            client.try_create_invoice_with_ref(&merchant, &empty, &usdc_id, &10_000_000, &expiry, &None),
+            Err(Ok(Error::InvalidReference))
+        );
+        assert_eq!(
+            client.try_create_invoice_with_ref(&merchant, &too_long, &usdc_id, &10_000_000, &expiry, &None),
+            Err(Ok(Error::InvalidReference))
+        );
+    }
+
+    #[test]
+    fn test_invoice_metadata() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let items = Bytes::from_slice(&env, b"2x espresso, 1x croissant");
+        let metadata = InvoiceMetadata {
+            order_id: Some(String::from_str(&env, "ORD-1001")),
+            description: Some(String::from_str(&env, "Breakfast at Minty Cafe")),
+            items_hash: Some(env.crypto().sha256(&items).to_bytes().into()),
+        };
+        
+        let amount = 10_000_000;
+        let expiry = env.ledger().timestamp() + 600;
+        let options = InvoiceOptions { metadata: metadata.clone(), ..InvoiceOptions::defaults(&env) };
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &amount, &expiry, &Some(options));
+        assert_eq!(client.get_invoice(&invoice_id).unwrap().metadata, metadata);
+        
+        // Both lifecycle events carry the metadata for reconciliation
+        let (_, _, data) = env.events().all().last().unwrap();
+        let data: (BytesN<32>, Address, i128, u64, InvoiceMetadata, Option<Address>) =
+            data.into_val(&env);
+        assert_eq!(data.4, metadata);
+        
+        client.pay(&invoice_id, &payer, &amount);
+        let (_, topics, data) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("paid"), merchant.clone()).into_val(&env));
+        let data: PaidEvent = data.into_val(&env);
+        assert_eq!(data, (invoice_id, payer, amount, metadata, None, FeeSource::NoFee));
+    }
+
+    #[test]
+    fn test_invoice_metadata_size_limits() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let expiry = env.ledger().timestamp() + 600;
+        
+        let long_order_id = InvoiceMetadata {
+            order_id: Some(String::from_bytes(&env, &[b'9'; 65])),
+            ..InvoiceMetadata::empty()
+        };
+        let long_description = InvoiceMetadata {
+            description: Some(String::from_bytes(&env, &[b'a'; 141])),
+            ..InvoiceMetadata::empty()
+        };
+        let short_hash = InvoiceMetadata {
+            items_hash: Some(Bytes::from_slice(&env, &[1; 16])),
+            ..InvoiceMetadata::empty()
+        };
+        for metadata in [long_order_id, long_description, short_hash] {
+            let options = InvoiceOptions { metadata, ..InvoiceOptions::defaults(&env) };
+            assert_eq!(
+                client.try_create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &Some(options)),
+                Err(Ok(Error::InvalidMetadataSize))
+            );
+        }
+        
+        // Exactly at the limits is fine
+        let at_limits = InvoiceMetadata {
+            order_id: Some(String::from_bytes(&env, &[b'9'; 64])),
+            description: Some(String::from_bytes(&env, &[b'a'; 140])),
+            items_hash: Some(Bytes::from_slice(&env, &[1; 32])),
+        };
+        let options = InvoiceOptions { metadata: at_limits, ..InvoiceOptions::defaults(&env) };
+        client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &Some(options));
+    }
+
+    fn line_item(env: &Env, sku: &str, quantity: u32, unit_price: i128, tax_bps: u32) -> LineItem {
+        LineItem {
+            sku: String::from_str(env, sku),
+            quantity,
+            unit_price,
+            tax_bps,
+        }
+    }
+
+    #[test]
+    fn test_create_itemized_invoice() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        // 2 x 0.1 USDC taxed at 10%, 1 x 0.25 USDC untaxed, 10% off
+        let items = vec![
+            &env,
+            line_item(&env, "MUG-01", 2, 1_000_000, 1000),
+            line_item(&env, "BOOK-7", 1, 2_500_000, 0),
+        ];
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_itemized_invoice(
+            &merchant, &usdc_id, &items, &Some(Discount::Percent(1000)), &expiry, &None,
+        );
+        
+        // Discount 450_000 splits 200_000 / 250_000; tax 10% of 1_800_000
+        let invoice = client.get_invoice(&invoice_id).unwrap();
+        assert_eq!(invoice.breakdown, InvoiceBreakdown {
+            items,
+            subtotal: 4_500_000,
+            discount: 450_000,
+            tax: 180_000,
+            total: 4_230_000,
+        });
+        assert_eq!(invoice.amount, 4_230_000);
+        
+        client.pay(&invoice_id, &payer, &4_230_000);
+        assert_eq!(usdc_client.balance(&merchant), 4_230_000);
+    }
+
+    #[test]
+    fn test_itemized_invoice_rounding() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let expiry = env.ledger().timestamp() + 600;
+        
+        // Tax rounds half up per line: 0.5 -> 1, 0.4 -> 0
+        let items = vec![
+            &env,
+            line_item(&env, "A", 1, 5, 1000),
+            line_item(&env, "B", 1, 4, 1000),
+        ];
+        let invoice_id = client.create_itemized_invoice(&merchant, &usdc_id, &items, &None, &expiry, &None);
+        let breakdown = client.get_invoice(&invoice_id).unwrap().breakdown;
+        assert_eq!((breakdown.subtotal, breakdown.tax, breakdown.total), (9, 1, 10));
+        
+        // A 20-unit discount over three 10-unit lines floors to 6 each; the
+        // remainder of 2 goes to the first line, leaving 2/4/4 taxable at 50%
+        let items = vec![
+            &env,
+            line_item(&env, "A", 1, 10, 5000),
+            line_item(&env, "B", 1, 10, 5000),
+            line_item(&env, "C", 1, 10, 5000),
+        ];
+        let invoice_id = client.create_itemized_invoice(
+            &merchant, &usdc_id, &items, &Some(Discount::Fixed(20)), &expiry, &None,
+        );
+        let breakdown = client.get_invoice(&invoice_id).unwrap().breakdown;
+        assert_eq!(breakdown.discount, 20);
+        assert_eq!(breakdown.tax, 5);
+        assert_eq!(breakdown.total, 15);
+        
+        // Plain invoices carry a flat breakdown
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        let breakdown = client.get_invoice(&invoice_id).unwrap().breakdown;
+        assert_eq!(breakdown, InvoiceBreakdown::flat(&env, 10_000_000));
+    }
+
+    #[test]
+    fn test_itemized_invoice_validation() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let expiry = env.ledger().timestamp() + 600;
+        let valid = line_item(&env, "SKU", 1, 1_000_000, 0);
+        
+        let cases = [
+            (vec![&env], None, Error::InvalidLineItem),
+            (vec![&env, line_item(&env, "SKU", 0, 1_000_000, 0)], None, Error::InvalidLineItem),
+            (vec![&env, line_item(&env, "SKU", 1, 0, 0)], None, Error::InvalidLineItem),
+            (vec![&env, line_item(&env, "SKU", 1, 1_000_000, 10_001)], None, Error::InvalidLineItem),
+            (vec![&env, line_item(&env, "", 1, 1_000_000, 0)], None, Error::InvalidLineItem),
+            (vec![&env, valid.clone()], Some(Discount::Percent(10_001)), Error::InvalidDiscount),
+            (vec![&env, valid.clone()], Some(Discount::Fixed(1_000_001)), Error::InvalidDiscount),
+            (vec![&env, valid.clone()], Some(Discount::Fixed(-1)), Error::InvalidDiscount),
+            (vec![&env, line_item(&env, "SKU", 2, i128::MAX, 0)], None, Error::ArithmeticOverflow),
+        ];
+        for (items, discount, error) in cases {
+            assert_eq!(
+                client.try_create_itemized_invoice(&merchant, &usdc_id, &items, &discount, &expiry, &None),
+                Err(Ok(error))
+            );
+        }
+        
+        // A 100% discount leaves nothing to pay, which the policy rejects
+        assert_eq!(
+            client.try_create_itemized_invoice(
+                &merchant, &usdc_id, &vec![&env, valid], &Some(Discount::Percent(10_000)), &expiry, &None,
+            ),
+            Err(Ok(Error::InvalidAmount))
+        );
+    }
+
+    /// Options for an invoice that accepts installments
+    fn partial_options(env: &Env) -> Option<InvoiceOptions> {
+        Some(InvoiceOptions { allow_partial: true, ..InvoiceOptions::defaults(env) })
+    }
+
+    #[test]
+    fn test_partial_payments_complete_invoice() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer_a = Address::generate(&env);
+        let payer_b = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&payer_a, &10_000_000);
+        usdc_admin_client.mint(&payer_b, &10_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &partial_options(&env));
+        
+        // First installment is held by the contract
+        client.pay(&invoice_id, &payer_a, &4_000_000);
+        let invoice = client.get_invoice(&invoice_id).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::PartiallyPaid);
+        assert_eq!(invoice.amount_paid, 4_000_000);
+        assert_eq!(invoice.payment_count, 1);
+        assert_eq!(usdc_client.balance(&client.address), 4_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        let (_, topics, data) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("part_paid"), merchant.clone()).into_val(&env));
+        let data: (BytesN<32>, Address, i128, i128) = data.into_val(&env);
+        assert_eq!(data, (invoice_id.clone(), payer_a.clone(), 4_000_000, 4_000_000));
+        
+        // Paying more than the outstanding balance is rejected
+        assert_eq!(
+            client.try_pay(&invoice_id, &payer_b, &6_000_001),
+            Err(Ok(Error::AmountMismatch))
+        );
+        assert_eq!(client.try_pay(&invoice_id, &payer_b, &0), Err(Ok(Error::AmountMismatch)));
+        
+        // The final installment settles the whole amount to the merchant
+        client.pay(&invoice_id, &payer_b, &6_000_000);
+        let invoice = client.get_invoice(&invoice_id).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Paid);
+        assert_eq!(invoice.amount_paid, 10_000_000);
+        assert_eq!(invoice.payer, Some(payer_b.clone()));
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        assert_eq!(usdc_client.balance(&merchant), 10_000_000);
+        
+        let payments = client.get_payments(&invoice_id);
+        assert_eq!(payments.len(), 2);
+        assert_eq!(payments.get(0).unwrap().payer, payer_a);
+        assert_eq!(payments.get(1).unwrap().amount, 6_000_000);
+        
+        assert_eq!(
+            client.try_pay(&invoice_id, &payer_b, &1),
+            Err(Ok(Error::InvoiceNotOpen))
+        );
+    }
+
+    #[test]
+    fn test_partial_payment_requires_opt_in() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &10_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        
+        assert_eq!(
+            client.try_pay(&invoice_id, &payer, &4_000_000),
+            Err(Ok(Error::AmountMismatch))
+        );
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Open));
+    }
+
+    #[test]
+    fn test_expired_partial_invoice_refunds_installments() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer_a = Address::generate(&env);
+        let payer_b = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&payer_a, &10_000_000);
+        usdc_admin_client.mint(&payer_b, &10_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &partial_options(&env));
+        client.pay(&invoice_id, &payer_a, &3_000_000);
+        client.pay(&invoice_id, &payer_b, &2_000_000);
+        
+        env.ledger().with_mut(|li| li.timestamp = expiry + 1);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Expired));
+        assert_eq!(
+            client.try_pay(&invoice_id, &payer_a, &5_000_000),
+            Err(Ok(Error::InvoiceExpired))
+        );
+        
+        assert_eq!(client.expire_invoices(&vec![&env, invoice_id.clone()]), 1);
+        assert_eq!(usdc_client.balance(&payer_a), 10_000_000);
+        assert_eq!(usdc_client.balance(&payer_b), 10_000_000);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        
+        let invoice = client.get_invoice(&invoice_id).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Expired);
+        
+        // A second sweep is a no-op
+        assert_eq!(client.expire_invoices(&vec![&env, invoice_id]), 0);
+        assert_eq!(usdc_client.balance(&payer_a), 10_000_000);
+    }
+
+    #[test]
+    fn test_refund_installments_to_each_payer() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer_a = Address::generate(&env);
+        let payer_b = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&payer_a, &10_000_000);
+        usdc_admin_client.mint(&payer_b, &10_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &partial_options(&env));
+        client.pay(&invoice_id, &payer_a, &7_000_000);
+        
+        // Installments cannot be refunded before the invoice is settled
+        assert_eq!(
+            client.try_refund(&invoice_id, &merchant, &7_000_000, &0),
+            Err(Ok(Error::InvoiceNotPaid))
+        );
+        
+        client.pay(&invoice_id, &payer_b, &3_000_000);
+        client.refund(&invoice_id, &merchant, &10_000_000, &0);
+        
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Refunded));
+        assert_eq!(usdc_client.balance(&payer_a), 10_000_000);
+        assert_eq!(usdc_client.balance(&payer_b), 10_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+    }
+
+    #[test]
+    fn test_open_amount_invoice() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_open_invoice(
+            &merchant, &usdc_id, &Some(1_000_000), &Some(50_000_000), &expiry, &None,
+        );
+        let invoice = client.get_invoice(&invoice_id).unwrap();
+        assert_eq!(invoice.amount_mode, AmountMode::Range(1_000_000, 50_000_000));
+        assert_eq!(invoice.amount, 0);
+        
+        // Amounts outside the range are rejected
+        assert_eq!(client.try_pay(&invoice_id, &payer, &999_999), Err(Ok(Error::AmountMismatch)));
+        assert_eq!(client.try_pay(&invoice_id, &payer, &50_000_001), Err(Ok(Error::AmountMismatch)));
+        
+        // The chosen amount becomes the invoice amount
+        client.pay(&invoice_id, &payer, &12_345_678);
+        let invoice = client.get_invoice(&invoice_id).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Paid);
+        assert_eq!(invoice.amount, 12_345_678);
+        assert_eq!(invoice.breakdown.total, 12_345_678);
+        assert_eq!(client.get_payment(&invoice_id, &0).unwrap().amount, 12_345_678);
+        assert_eq!(usdc_client.balance(&merchant), 12_345_678);
+        
+        // And is what a refund must return
+        client.refund(&invoice_id, &merchant, &12_345_678, &0);
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+    }
+
+    #[test]
+    fn test_open_amount_invoice_bounds() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        let expiry = env.ledger().timestamp() + 600;
+        
+        client.set_merchant_policy(&admin, &merchant, &CheckoutPolicy {
+            min_amount: 100,
+            max_amount: 10_000_000,
+            min_expiry_secs: 300,
+            max_expiry_secs: 3600,
+        });
+        
+        // Missing bounds default to the merchant's policy limits
+        let invoice_id = client.create_open_invoice(&merchant, &usdc_id, &None, &None, &expiry, &None);
+        let invoice = client.get_invoice(&invoice_id).unwrap();
+        assert_eq!(invoice.amount_mode, AmountMode::Range(100, 10_000_000));
+        assert_eq!(client.try_pay(&invoice_id, &payer, &99), Err(Ok(Error::AmountMismatch)));
+        client.pay(&invoice_id, &payer, &100);
+        
+        let cases = [
+            (Some(0), None),
+            (Some(99), None),
+            (None, Some(10_000_001)),
+            (Some(5_000), Some(4_999)),
+        ];
+        for (min_amount, max_amount) in cases {
+            assert_eq!(
+                client.try_create_open_invoice(&merchant, &usdc_id, &min_amount, &max_amount, &expiry, &None),
+                Err(Ok(Error::InvalidAmount))
+            );
+        }
+        
+        // Open amounts cannot be combined with installments
+        assert_eq!(
+            client.try_create_open_invoice(&merchant, &usdc_id, &None, &None, &expiry, &partial_options(&env)),
+            Err(Ok(Error::InvalidAmount))
+        );
+    }
+
+    #[test]
+    fn test_payment_link_reusable() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer_a = Address::generate(&env);
+        let payer_b = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&payer_a, &100_000_000);
+        usdc_admin_client.mint(&payer_b, &100_000_000);
+        
+        let link_id = client.create_payment_link(&merchant, &usdc_id, &30_000_000, &None, &None, &None);
+        
+        assert_eq!(client.pay_link(&link_id, &payer_a, &30_000_000), 0);
+        assert_eq!(client.pay_link(&link_id, &payer_b, &30_000_000), 1);
+        assert_eq!(client.pay_link(&link_id, &payer_a, &30_000_000), 2);
+        
+        let (_, topics, data) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("link_paid"), merchant.clone()).into_val(&env));
+        let data: (BytesN<32>, Address, i128, u32, FeeSource) = data.into_val(&env);
+        assert_eq!(data, (link_id.clone(), payer_a.clone(), 30_000_000, 2, FeeSource::NoFee));
+        
+        let link = client.get_payment_link(&link_id).unwrap();
+        assert_eq!(link.use_count, 3);
+        assert!(link.active);
+        assert_eq!(client.get_link_payment(&link_id, &1).unwrap().payer, payer_b);
+        assert_eq!(client.get_link_payment(&link_id, &3), None);
+        assert_eq!(usdc_client.balance(&merchant), 90_000_000);
+        
+        assert_eq!(
+            client.try_pay_link(&link_id, &payer_a, &29_999_999),
+            Err(Ok(Error::AmountMismatch))
+        );
+    }
+
+    #[test]
+    fn test_payment_link_limits() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        let now = env.ledger().timestamp();
+        
+        // Usage cap
+        let capped = client.create_payment_link(&merchant, &usdc_id, &1_000_000, &Some(2), &None, &None);
+        client.pay_link(&capped, &payer, &1_000_000);
+        client.pay_link(&capped, &payer, &1_000_000);
+        assert_eq!(
+            client.try_pay_link(&capped, &payer, &1_000_000),
+            Err(Ok(Error::LinkInactive))
+        );
+        
+        // End date is inclusive
+        let dated = client.create_payment_link(&merchant, &usdc_id, &1_000_000, &None, &Some(now + 1000), &None);
+        env.ledger().with_mut(|li| li.timestamp = now + 1000);
+        client.pay_link(&dated, &payer, &1_000_000);
+        env.ledger().with_mut(|li| li.timestamp = now + 1001);
+        assert_eq!(
+            client.try_pay_link(&dated, &payer, &1_000_000),
+            Err(Ok(Error::LinkInactive))
+        );
+        
+        // Invalid limits
+        assert_eq!(
+            client.try_create_payment_link(&merchant, &usdc_id, &1_000_000, &Some(0), &None, &None),
+            Err(Ok(Error::InvalidUsageLimit))
+        );
+        assert_eq!(
+            client.try_create_payment_link(&merchant, &usdc_id, &1_000_000, &None, &Some(now + 1001), &None),
+            Err(Ok(Error::InvalidUsageLimit))
+        );
+        assert_eq!(
+            client.try_create_payment_link(&merchant, &usdc_id, &0, &None, &None, &None),
+            Err(Ok(Error::InvalidAmount))
+        );
+        
+        let missing = BytesN::from_array(&env, &[9; 32]);
+        assert_eq!(
+            client.try_pay_link(&missing, &payer, &1_000_000),
+            Err(Ok(Error::LinkNotFound))
+        );
+    }
+
+    #[test]
+    fn test_deactivate_payment_link() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let other = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let link_id = client.create_payment_link(&merchant, &usdc_id, &1_000_000, &None, &None, &None);
+        client.pay_link(&link_id, &payer, &1_000_000);
+        
+        assert_eq!(
+            client.try_deactivate_link(&link_id, &other),
+            Err(Ok(Error::Unauthorized))
+        );
+        client.deactivate_link(&link_id, &merchant);
+        
+        let (_, topics, _) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("link_off"), merchant.clone()).into_val(&env));
+        assert!(!client.get_payment_link(&link_id).unwrap().active);
+        assert_eq!(
+            client.try_pay_link(&link_id, &payer, &1_000_000),
+            Err(Ok(Error::LinkInactive))
+        );
+        assert_eq!(
+            client.try_deactivate_link(&link_id, &merchant),
+            Err(Ok(Error::LinkInactive))
+        );
+        
+        // Earlier payments stay on record
+        assert_eq!(client.get_link_payment(&link_id, &0).unwrap().amount, 1_000_000);
+    }
+
+    #[test]
+    fn test_cancel_invoice() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let other = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        let expiry = env.ledger().timestamp() + 600;
+        
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        assert_eq!(
+            client.try_cancel_invoice(&invoice_id, &other),
+            Err(Ok(Error::Unauthorized))
+        );
+        client.cancel_invoice(&invoice_id, &merchant);
+        
+        let (_, topics, _) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("cancelled"), merchant.clone()).into_val(&env));
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Cancelled));
+        assert_eq!(
+            client.try_pay(&invoice_id, &payer, &10_000_000),
+            Err(Ok(Error::InvoiceNotOpen))
+        );
+        assert_eq!(
+            client.try_cancel_invoice(&invoice_id, &merchant),
+            Err(Ok(Error::InvoiceNotOpen))
+        );
+        
+        // Cancelled invoices never read as expired and are skipped by the sweep
+        env.ledger().with_mut(|li| li.timestamp = expiry + 1);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Cancelled));
+        assert_eq!(client.expire_invoices(&vec![&env, invoice_id]), 0);
+        
+        // Installments are returned when a partially paid invoice is cancelled
+        let expiry = env.ledger().timestamp() + 600;
+        let partial_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &partial_options(&env));
+        client.pay(&partial_id, &payer, &4_000_000);
+        client.cancel_invoice(&partial_id, &merchant);
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        
+        // Paid invoices must be refunded instead
+        let paid_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&paid_id, &payer, &10_000_000);
+        assert_eq!(
+            client.try_cancel_invoice(&paid_id, &merchant),
+            Err(Ok(Error::InvoiceNotOpen))
+        );
+    }
+
+    #[test]
+    fn test_amend_invoice() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        let now = env.ledger().timestamp();
+        
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &100_000_000, &(now + 600), &None);
+        assert_eq!(client.get_invoice(&invoice_id).unwrap().revision, 0);
+        
+        // Fix the amount, then extend the expiry
+        assert_eq!(client.amend_invoice(&invoice_id, &merchant, &Some(10_000_000), &None), 1);
+        let (_, topics, data) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("amended"), merchant.clone()).into_val(&env));
+        let data: (BytesN<32>, u32, i128, u64) = data.into_val(&env);
+        assert_eq!(data, (invoice_id.clone(), 1, 10_000_000, now + 600));
+        
+        assert_eq!(client.amend_invoice(&invoice_id, &merchant, &None, &Some(now + 1200)), 2);
+        let invoice = client.get_invoice(&invoice_id).unwrap();
+        assert_eq!(invoice.amount, 10_000_000);
+        assert_eq!(invoice.breakdown.total, 10_000_000);
+        assert_eq!(invoice.expiry, now + 1200);
+        
+        // Payers pinned to an older revision are refused
+        assert_eq!(
+            client.try_pay_revision(&invoice_id, &payer, &10_000_000, &1),
+            Err(Ok(Error::RevisionMismatch))
+        );
+        client.pay_revision(&invoice_id, &payer, &10_000_000, &2);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Paid));
+        
+        // Paid invoices can no longer be amended
+        assert_eq!(
+            client.try_amend_invoice(&invoice_id, &merchant, &Some(1), &None),
+            Err(Ok(Error::InvoiceNotOpen))
+        );
+    }
+
+    #[test]
+    fn test_amend_invoice_validation() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let other = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        let now = env.ledger().timestamp();
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &(now + 600), &None);
+        
+        let cases = [
+            (other.clone(), Some(1), None, Error::Unauthorized),
+            (merchant.clone(), None, None, Error::InvalidAmendment),
+            (merchant.clone(), Some(0), None, Error::InvalidAmount),
+            (merchant.clone(), None, Some(now + 600), Error::InvalidExpiry),
+            (merchant.clone(), None, Some(now + 3601), Error::InvalidExpiry),
+        ];
+        for (caller, amount, expiry, error) in cases {
+            assert_eq!(
+                client.try_amend_invoice(&invoice_id, &caller, &amount, &expiry),
+                Err(Ok(error))
+            );
+        }
+        assert_eq!(client.get_invoice(&invoice_id).unwrap().revision, 0);
+        
+        // Open-amount invoices have no fixed amount to change
+        let open_id = client.create_open_invoice(&merchant, &usdc_id, &None, &None, &(now + 600), &None);
+        assert_eq!(
+            client.try_amend_invoice(&open_id, &merchant, &Some(5), &None),
+            Err(Ok(Error::InvalidAmount))
+        );
+        
+        // Nor can invoices that already received an installment
+        let partial_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &(now + 600), &partial_options(&env));
+        client.pay(&partial_id, &payer, &1_000_000);
+        assert_eq!(
+            client.try_amend_invoice(&partial_id, &merchant, &None, &Some(now + 1200)),
+            Err(Ok(Error::InvoiceNotOpen))
+        );
+        
+        // Lapsed invoices cannot be revived
+        env.ledger().with_mut(|li| li.timestamp = now + 601);
+        assert_eq!(
+            client.try_amend_invoice(&invoice_id, &merchant, &None, &Some(now + 1200)),
+            Err(Ok(Error::InvoiceExpired))
+        );
+    }
+
+    #[test]
+    fn test_designated_payer() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let treasury = Address::generate(&env);
+        let stranger = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&treasury, &100_000_000);
+        usdc_admin_client.mint(&stranger, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let options = InvoiceOptions {
+            designated_payer: Some(treasury.clone()),
+            ..InvoiceOptions::defaults(&env)
+        };
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &Some(options));
+        
+        // The restriction is announced and stored
+        let (_, _, data) = env.events().all().last().unwrap();
+        let data: (BytesN<32>, Address, i128, u64, InvoiceMetadata, Option<Address>) =
+            data.into_val(&env);
+        assert_eq!(data.5, Some(treasury.clone()));
+        assert_eq!(client.get_invoice(&invoice_id).unwrap().designated_payer, Some(treasury.clone()));
+        
+        assert_eq!(
+            client.try_pay(&invoice_id, &stranger, &10_000_000),
+            Err(Ok(Error::PayerNotAllowed))
+        );
+        client.pay(&invoice_id, &treasury, &10_000_000);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Paid));
+        
+        // Unrestricted invoices accept anyone
+        let open_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        assert_eq!(client.get_invoice(&open_id).unwrap().designated_payer, None);
+        client.pay(&open_id, &stranger, &10_000_000);
+    }
+
+    /// A batch of `count` valid invoice specs
+    fn invoice_specs(env: &Env, token: &Address, count: u32) -> soroban_sdk::Vec<InvoiceSpec> {
+        let expiry = env.ledger().timestamp() + 600;
+        let mut specs = vec![env];
+        for i in 0..count {
+            specs.push_back(InvoiceSpec {
+                token: token.clone(),
+                amount: 1_000_000 + i as i128,
+                expiry,
+                options: InvoiceOptions::defaults(env),
+            });
+        }
+        specs
+    }
+
+    #[test]
+    fn test_create_invoices() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        
+        let ids = client.create_invoices(&merchant, &invoice_specs(&env, &usdc_id, 3));
+        
+        // One authorization covers the whole batch
+        assert_eq!(env.auths().len(), 1);
+        assert_eq!(ids.len(), 3);
+        for (i, invoice_id) in ids.iter().enumerate() {
+            let invoice = client.get_invoice(&invoice_id).unwrap();
+            assert_eq!(invoice.merchant, merchant);
+            assert_eq!(invoice.amount, 1_000_000 + i as i128);
+            assert_eq!(invoice.status, InvoiceStatus::Open);
+        }
+    }
+
+    #[test]
+    fn test_create_invoices_all_or_nothing() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        
+        let mut specs = invoice_specs(&env, &usdc_id, 3);
+        let mut bad = specs.get(2).unwrap();
+        bad.expiry = env.ledger().timestamp();
+        specs.set(2, bad);
+        assert_eq!(
+            client.try_create_invoices(&merchant, &specs),
+            Err(Ok(Error::InvalidExpiry))
+        );
+        
+        // The valid entries before the failure were rolled back too
+        let counter: Option<u64> = env.as_contract(&client.address, || {
+            env.storage().persistent().get(&DataKey::InvoiceCounter(merchant.clone()))
+        });
+        assert_eq!(counter, None);
+        
+        assert_eq!(
+            client.try_create_invoices(&merchant, &vec![&env]),
+            Err(Ok(Error::InvalidBatchSize))
+        );
+        assert_eq!(
+            client.try_create_invoices(&merchant, &invoice_specs(&env, &usdc_id, MAX_BATCH_SIZE + 1)),
+            Err(Ok(Error::InvalidBatchSize))
+        );
+    }
+
+    #[test]
+    fn test_create_invoices_budget() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let specs = invoice_specs(&env, &usdc_id, MAX_BATCH_SIZE);
+        
+        // Measured natively, a full batch uses about 2.7M CPU instructions
+        // and 0.4 MB of memory. Wasm execution costs more than this native
+        // run, so require it to stay under a quarter of the network limits
+        // (100M instructions, 40 MiB); the binding constraint on batch size
+        // is the 25-entry write limit behind `MAX_BATCH_SIZE`.
+        env.budget().reset_default();
+        client.create_invoices(&merchant, &specs);
+        assert!(env.budget().cpu_instruction_cost() < 25_000_000);
+        assert!(env.budget().memory_bytes_cost() < 10 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_pay_many() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant_a = Address::generate(&env);
+        let merchant_b = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let id_a = client.create_invoice(&merchant_a, &usdc_id, &10_000_000, &expiry, &None);
+        let id_b = client.create_invoice(&merchant_b, &usdc_id, &20_000_000, &expiry, &None);
+        let items = vec![&env, (id_a.clone(), 10_000_000i128), (id_b.clone(), 20_000_000i128)];
+        
+        let cart_id = client.pay_many(&payer, &items);
+        
+        // One authorization covers the whole cart
+        assert_eq!(env.auths().len(), 1);
+        assert_eq!(client.get_invoice_status(&id_a), Some(InvoiceStatus::Paid));
+        assert_eq!(client.get_invoice_status(&id_b), Some(InvoiceStatus::Paid));
+        assert_eq!(usdc_client.balance(&merchant_a), 10_000_000);
+        assert_eq!(usdc_client.balance(&merchant_b), 20_000_000);
+        assert_eq!(usdc_client.balance(&payer), 70_000_000);
+        
+        // Every paid event carries the cart ID
+        let mut paid = vec![&env];
+        for (_, topics, data) in env.events().all().iter() {
+            if topics.get(0).unwrap().shallow_eq(&symbol_short!("paid").to_val()) {
+                let data: PaidEvent = data.into_val(&env);
+                paid.push_back(data.4);
+            }
+        }
+        assert_eq!(paid, vec![&env, Some(cart_id.clone()), Some(cart_id)]);
+    }
+
+    #[test]
+    fn test_pay_many_all_or_nothing() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let designated = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let good = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        let other = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        let restricted = client.create_invoice(
+            &merchant,
+            &usdc_id,
+            &10_000_000,
+            &expiry,
+            &Some(InvoiceOptions { designated_payer: Some(designated), ..InvoiceOptions::defaults(&env) }),
+        );
+        
+        let cases = [
+            (vec![&env, (good.clone(), 10_000_000i128), (other, 5i128)], Error::AmountMismatch),
+            (vec![&env, (good.clone(), 10_000_000i128), (good.clone(), 10_000_000i128)], Error::InvoiceNotOpen),
+            (vec![&env, (good.clone(), 10_000_000i128), (restricted, 10_000_000i128)], Error::PayerNotAllowed),
+            (vec![&env], Error::InvalidBatchSize),
+        ];
+        for (items, error) in cases {
+            assert_eq!(client.try_pay_many(&payer, &items), Err(Ok(error)));
+        }
+        
+        // Nothing was settled by the failed carts
+        assert_eq!(client.get_invoice_status(&good), Some(InvoiceStatus::Open));
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+        
+        // A cart that could write more than a transaction allows is
+        // refused before any item is tried
+        let mut too_many = vec![&env];
+        for _ in 0..TX_WRITE_ENTRIES / 2 {
+            too_many.push_back((good.clone(), 10_000_000i128));
+        }
+        assert_eq!(client.try_pay_many(&payer, &too_many), Err(Ok(Error::InvalidBatchSize)));
+    }
+
+    #[test]
+    fn test_pay_many_footprint() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let payer = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let (client, admin, _) = setup(&env);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 300, FeeRefund::Returned)));
+        
+        // Worst case: every item is the final installment of a fully split
+        // invoice, in its own token and for its own merchant, writing the
+        // invoice, its payment, the merchant's volume and eight balances
+        let mut items = vec![&env];
+        for _ in 0..3 {
+            let merchant = Address::generate(&env);
+            let (token_id, _) = create_usdc(&env);
+            client.add_token(&admin, &token_id);
+            token::StellarAssetClient::new(&env, &token_id).mint(&payer, &10_000_000);
+            
+            let recipients: [Address; MAX_SPLITS as usize] = core::array::from_fn(|_| Address::generate(&env));
+            let shares: [(&Address, u32); MAX_SPLITS as usize] =
+                core::array::from_fn(|index| (&recipients[index], 2_000));
+            let mut options = split_options(&env, &shares).unwrap();
+            options.allow_partial = true;
+            
+            let expiry = env.ledger().timestamp() + 600;
+            let invoice_id = client.create_invoice(&merchant, &token_id, &10_000_000, &expiry, &Some(options));
+            client.pay(&invoice_id, &payer, &4_000_000);
+            items.push_back((invoice_id, 6_000_000i128));
+        }
+        
+        // Three such items, plus the payer's nonce, would write 34 entries
+        assert_eq!(client.try_pay_many(&payer, &items), Err(Ok(Error::InvalidBatchSize)));
+        items.pop_back();
+        
+        // Count the ledger entries two of them create or change
+        let before = env.to_ledger_snapshot().ledger_entries;
+        env.budget().reset_default();
+        client.pay_many(&payer, &items);
+        let cpu = env.budget().cpu_instruction_cost();
+        let memory = env.budget().memory_bytes_cost();
+        let written = env
+            .to_ledger_snapshot()
+            .ledger_entries
+            .iter()
+            .filter(|(key, (entry, _))| {
+                !before.iter().any(|(old_key, (old_entry, _))| old_key == key && old_entry == entry)
+            })
+            .count() as u32;
+        assert_eq!(written, 2 * (3 + MAX_SPLITS + 3) + 1);
+        assert!(written <= TX_WRITE_ENTRIES);
+        
+        // Measured natively, the cart uses about 3.3M CPU instructions and
+        // 0.6 MB of memory; hold it to the same bounds as `create_invoices`
+        assert!(cpu < 25_000_000);
+        assert!(memory < 10 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_pay_many_marketplace_cart() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        // Plain invoices from six merchants in one token: each writes its
+        // invoice, its payment, the merchant's balance and volume, and they
+        // share the payer's balance and nonce
+        let expiry = env.ledger().timestamp() + 600;
+        let mut merchants = vec![&env];
+        let mut items = vec![&env];
+        for _ in 0..6 {
+            let merchant = Address::generate(&env);
+            let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+            merchants.push_back(merchant);
+            items.push_back((invoice_id, 10_000_000i128));
+        }
+        
+        // Six would write 26 entries; five write 22
+        assert_eq!(client.try_pay_many(&payer, &items), Err(Ok(Error::InvalidBatchSize)));
+        items.pop_back();
+        
+        let before = env.to_ledger_snapshot().ledger_entries;
+        client.pay_many(&payer, &items);
+        let written = env
+            .to_ledger_snapshot()
+            .ledger_entries
+            .iter()
+            .filter(|(key, (entry, _))| {
+                !before.iter().any(|(old_key, (old_entry, _))| old_key == key && old_entry == entry)
+            })
+            .count() as u32;
+        assert_eq!(written, 5 * 4 + 2);
+        
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        for merchant in merchants.iter().take(5) {
+            assert_eq!(usdc_client.balance(&merchant), 10_000_000);
+        }
+        assert_eq!(usdc_client.balance(&payer), 50_000_000);
+    }
+
+    /// Options splitting revenue between the given recipients
+    fn split_options(env: &Env, shares: &[(&Address, u32)]) -> Option<InvoiceOptions> {
+        let mut splits = vec![env];
+        for (recipient, bps) in shares {
+            splits.push_back(SplitShare { recipient: (*recipient).clone(), bps: *bps });
+        }
+        Some(InvoiceOptions { splits, ..InvoiceOptions::defaults(env) })
+    }
+
+    #[test]
+    fn test_split_payment_and_refund() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let seller = Address::generate(&env);
+        let platform = Address::generate(&env);
+        let courier = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let options = split_options(&env, &[(&seller, 3333), (&platform, 3333), (&courier, 3334)]);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_001, &expiry, &options);
+        assert_eq!(client.get_invoice(&invoice_id).unwrap().splits, options.unwrap().splits);
+        
+        // Shares are rounded down and the remainder goes to the first recipient
+        client.pay(&invoice_id, &payer, &10_000_001);
+        assert_eq!(usdc_client.balance(&seller), 3_333_001);
+        assert_eq!(usdc_client.balance(&platform), 3_333_000);
+        assert_eq!(usdc_client.balance(&courier), 3_334_000);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        
+        let split_events = env
+            .events()
+            .all()
+            .iter()
+            .filter(|(_, topics, _)| {
+                topics.get(0).unwrap().shallow_eq(&symbol_short!("split").to_val())
+            })
+            .count();
+        assert_eq!(split_events, 3);
+        
+        // A refund claws back exactly what each recipient received
+        client.refund(&invoice_id, &merchant, &10_000_001, &0);
+        assert_eq!(usdc_client.balance(&seller), 0);
+        assert_eq!(usdc_client.balance(&platform), 0);
+        assert_eq!(usdc_client.balance(&courier), 0);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Refunded));
+    }
+
+    #[test]
+    fn test_split_installments() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let platform = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let mut options = split_options(&env, &[(&merchant, 9000), (&platform, 1000)]).unwrap();
+        options.allow_partial = true;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &Some(options));
+        
+        // Nothing is distributed until the invoice is complete
+        client.pay(&invoice_id, &payer, &4_000_000);
+        assert_eq!(usdc_client.balance(&platform), 0);
+        client.pay(&invoice_id, &payer, &6_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 9_000_000);
+        assert_eq!(usdc_client.balance(&platform), 1_000_000);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        
+        // The merchant can be one of the recipients of a clawback
+        client.refund(&invoice_id, &merchant, &10_000_000, &0);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        assert_eq!(usdc_client.balance(&platform), 0);
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+    }
+
+    #[test]
+    fn test_invalid_splits() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let a = Address::generate(&env);
+        let b = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let expiry = env.ledger().timestamp() + 600;
+        
+        let many: [Address; 6] = core::array::from_fn(|_| Address::generate(&env));
+        let too_many: [(&Address, u32); 6] =
+            core::array::from_fn(|i| (&many[i], if i == 0 { 5000 } else { 1000 }));
+        
+        let cases = [
+            split_options(&env, &[(&a, 5000), (&b, 4999)]),
+            split_options(&env, &[(&a, 5000), (&b, 5001)]),
+            split_options(&env, &[(&a, 10_000), (&b, 0)]),
+            split_options(&env, &[(&a, 5000), (&a, 5000)]),
+            split_options(&env, &too_many),
+        ];
+        for options in cases {
+            assert_eq!(
+                client.try_create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &options),
+                Err(Ok(Error::InvalidSplit))
+            );
+        }
+        
+        // A single recipient taking everything is fine
+        client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &split_options(&env, &[(&a, 10_000)]));
+    }
+
+    /// Fee settings charging `bps`
+    fn fee_config(collector: &Address, bps: u32, refund: FeeRefund) -> FeeConfig {
+        FeeConfig { bps, collector: collector.clone(), refund }
+    }
+
+    #[test]
+    fn test_set_fee_config() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let fee_manager = Address::generate(&env);
+        let outsider = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let (client, _, _) = setup(&env);
+        assert_eq!(client.get_fee_config(), None);
+        
+        let fee = fee_config(&collector, 250, FeeRefund::Returned);
+        assert_eq!(
+            client.try_set_fee_config(&outsider, &Some(fee.clone())),
+            Err(Ok(Error::UnauthorizedAccess))
+        );
+        
+        client.grant_role(&Role::FeeManager, &fee_manager);
+        client.set_fee_config(&fee_manager, &Some(fee.clone()));
+        assert_eq!(client.get_fee_config(), Some(fee));
+        let (_, topics, _) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("fee_cfg"), fee_manager.clone()).into_val(&env));
+        
+        let invalid = fee_config(&collector, 10_001, FeeRefund::Kept);
+        assert_eq!(
+            client.try_set_fee_config(&fee_manager, &Some(invalid)),
+            Err(Ok(Error::InvalidFeeConfig))
+        );
+        
+        client.set_fee_config(&fee_manager, &None);
+        assert_eq!(client.get_fee_config(), None);
+    }
+
+    #[test]
+    fn test_pay_deducts_platform_fee() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 250, FeeRefund::Returned)));
+        client.set_min_fee(&admin, &usdc_id, &100_000);
+        
+        // 2.5% of 10 USDC
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        
+        let payment = client.get_payment(&invoice_id, &0).unwrap();
+        assert_eq!((payment.amount, payment.fee, payment.net), (10_000_000, 250_000, 9_750_000));
+        assert_eq!(usdc_client.balance(&merchant), 9_750_000);
+        assert_eq!(usdc_client.balance(&collector), 250_000);
+        let invoice = client.get_invoice(&invoice_id).unwrap();
+        assert_eq!(invoice.fee_total, 250_000);
+        assert_eq!(invoice.fee_collector, Some(collector.clone()));
+        
+        // The flat minimum applies to small payments
+        let small_id = client.create_invoice(&merchant, &usdc_id, &1_000_000, &expiry, &None);
+        client.pay(&small_id, &payer, &1_000_000);
+        let payment = client.get_payment(&small_id, &0).unwrap();
+        assert_eq!((payment.fee, payment.net), (100_000, 900_000));
+        
+        // And never exceeds the payment itself
+        let tiny_id = client.create_invoice(&merchant, &usdc_id, &50_000, &expiry, &None);
+        client.pay(&tiny_id, &payer, &50_000);
+        let payment = client.get_payment(&tiny_id, &0).unwrap();
+        assert_eq!((payment.fee, payment.net), (50_000, 0));
+    }
+
+    #[test]
+    fn test_refund_returns_fee() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Returned)));
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        
+        assert_eq!(
+            client.try_refund(&invoice_id, &merchant, &10_000_001, &0),
+            Err(Ok(Error::AmountMismatch))
+        );
+        client.refund(&invoice_id, &merchant, &10_000_000, &0);
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        assert_eq!(usdc_client.balance(&collector), 0);
+    }
+
+    #[test]
+    fn test_refund_returned_fee_needs_collector() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        // Paid before any fee is configured: `Returned`, but no fee to return
+        let expiry = env.ledger().timestamp() + 600;
+        let free_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&free_id, &payer, &10_000_000);
+        assert_eq!(client.get_payment(&free_id, &0).unwrap().fee_refund, FeeRefund::Returned);
+        
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Returned)));
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        
+        // The merchant alone authorizes a refund that returns no fee
+        env.mock_auths(&[MockAuth {
+            address: &merchant,
+            invoke: &MockAuthInvoke {
+                contract: &client.address,
+                fn_name: "refund",
+                args: (free_id.clone(), merchant.clone(), 5_000_000i128, 0u32).into_val(&env),
+                sub_invokes: &[MockAuthInvoke {
+                    contract: &usdc_id,
+                    fn_name: "transfer",
+                    args: (merchant.clone(), payer.clone(), 5_000_000i128).into_val(&env),
+                    sub_invokes: &[],
+                }],
+            },
+        }]);
+        client.refund(&free_id, &merchant, &5_000_000, &0);
+        
+        // But not one returning part of a fee: the collector must co-sign
+        env.mock_auths(&[MockAuth {
+            address: &merchant,
+            invoke: &MockAuthInvoke {
+                contract: &client.address,
+                fn_name: "refund",
+                args: (invoice_id.clone(), merchant.clone(), 5_000_000i128, 0u32).into_val(&env),
+                sub_invokes: &[MockAuthInvoke {
+                    contract: &usdc_id,
+                    fn_name: "transfer",
+                    args: (merchant.clone(), payer.clone(), 4_950_000i128).into_val(&env),
+                    sub_invokes: &[],
+                }],
+            },
+        }]);
+        assert!(client.try_refund(&invoice_id, &merchant, &5_000_000, &0).is_err());
+        assert_eq!(client.get_invoice(&invoice_id).unwrap().refunded_total, 0);
+        
+        env.mock_all_auths();
+        client.refund(&invoice_id, &merchant, &5_000_000, &0);
+        assert_eq!(env.auths().len(), 2);
+        assert_eq!((env.auths()[0].0.clone(), env.auths()[1].0.clone()), (merchant, collector.clone()));
+        assert_eq!(usdc_client.balance(&collector), 50_000);
+        assert_eq!(usdc_client.balance(&payer), 100_000_000 - 10_000_000);
+    }
+
+    #[test]
+    fn test_refund_keeps_fee() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Kept)));
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        
+        assert_eq!(
+            client.try_refund(&invoice_id, &merchant, &10_000_000, &0),
+            Err(Ok(Error::AmountMismatch))
+        );
+        client.refund(&invoice_id, &merchant, &9_900_000, &0);
+        assert_eq!(usdc_client.balance(&payer), 99_900_000);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        assert_eq!(usdc_client.balance(&collector), 100_000);
+        
+        let (_, _, data) = env.events().all().last().unwrap();
+        let data: (BytesN<32>, Address, i128) = data.into_val(&env);
+        assert_eq!(data, (invoice_id, payer, 9_900_000));
+    }
+
+    #[test]
+    fn test_refund_uses_fee_setting_of_payment() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Returned)));
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let returned_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&returned_id, &payer, &10_000_000);
+        assert_eq!(client.get_payment(&returned_id, &0).unwrap().fee_refund, FeeRefund::Returned);
+        
+        // Switching the setting between partial refunds changes nothing for
+        // a payment already made
+        client.refund(&returned_id, &merchant, &5_000_000, &0);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Kept)));
+        client.refund(&returned_id, &merchant, &5_000_000, &0);
+        let invoice = client.get_invoice(&returned_id).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Refunded);
+        assert_eq!((invoice.refunded_total, invoice.fees_refunded), (10_000_000, 100_000));
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+        assert_eq!(usdc_client.balance(&collector), 0);
+        
+        // A payment made while fees are kept stays that way
+        let kept_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&kept_id, &payer, &10_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Returned)));
+        assert_eq!(
+            client.try_refund(&kept_id, &merchant, &10_000_000, &0),
+            Err(Ok(Error::AmountMismatch))
+        );
+        client.refund(&kept_id, &merchant, &9_900_000, &0);
+        assert_eq!(client.get_invoice_status(&kept_id), Some(InvoiceStatus::Refunded));
+        assert_eq!(usdc_client.balance(&collector), 100_000);
+    }
+
+    #[test]
+    fn test_fee_on_installments_and_links() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Returned)));
+        
+        // Installment fees are held with the funds until the invoice completes
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &partial_options(&env));
+        client.pay(&invoice_id, &payer, &4_000_000);
+        assert_eq!(client.get_payment(&invoice_id, &0).unwrap().fee, 40_000);
+        assert_eq!(usdc_client.balance(&collector), 0);
+        client.pay(&invoice_id, &payer, &6_000_000);
+        assert_eq!(usdc_client.balance(&collector), 100_000);
+        assert_eq!(usdc_client.balance(&merchant), 9_900_000);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        
+        // Payment links are charged per use
+        let link_id = client.create_payment_link(&merchant, &usdc_id, &1_000_000, &None, &None, &None);
+        client.pay_link(&link_id, &payer, &1_000_000);
+        let payment = client.get_link_payment(&link_id, &0).unwrap();
+        assert_eq!((payment.fee, payment.net), (10_000, 990_000));
+        assert_eq!(usdc_client.balance(&collector), 110_000);
+    }
+
+    #[test]
+    fn test_min_fee_spread_over_installments() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 0, FeeRefund::Returned)));
+        client.set_min_fee(&admin, &usdc_id, &100_001);
+        
+        // Four installments together pay the minimum once, as one payment would
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &partial_options(&env));
+        for _ in 0..4 {
+            client.pay(&invoice_id, &payer, &2_500_000);
+        }
+        let fees: [i128; 4] =
+            core::array::from_fn(|i| client.get_payment(&invoice_id, &(i as u32)).unwrap().fee);
+        assert_eq!(fees, [25_000, 25_000, 25_000, 25_001]);
+        assert_eq!(client.get_invoice(&invoice_id).unwrap().fee_total, 100_001);
+        assert_eq!(usdc_client.balance(&collector), 100_001);
+        assert_eq!(usdc_client.balance(&merchant), 10_000_000 - 100_001);
+    }
+
+    #[test]
+    fn test_min_fee_per_token() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let fee_manager = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let (other_id, _) = create_usdc(&env);
+        client.add_token(&admin, &other_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        token::StellarAssetClient::new(&env, &other_id).mint(&payer, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Returned)));
+        client.grant_role(&Role::FeeManager, &fee_manager);
+        
+        assert_eq!(client.try_set_min_fee(&payer, &usdc_id, &1), Err(Ok(Error::UnauthorizedAccess)));
+        assert_eq!(client.try_set_min_fee(&fee_manager, &usdc_id, &-1), Err(Ok(Error::InvalidFeeConfig)));
+        client.set_min_fee(&fee_manager, &usdc_id, &50_000);
+        let (_, topics, data) = env.events().all().last().unwrap();
+        assert_eq!(topics, (symbol_short!("min_fee"), fee_manager.clone()).into_val(&env));
+        let data: (Address, i128) = data.into_val(&env);
+        assert_eq!(data, (usdc_id.clone(), 50_000));
+        assert_eq!(client.get_min_fee(&usdc_id), 50_000);
+        assert_eq!(client.get_min_fee(&other_id), 0);
+        
+        // The same small payment owes the minimum in USDC only
+        let expiry = env.ledger().timestamp() + 600;
+        for (token_id, fee) in [(&usdc_id, 50_000), (&other_id, 10_000)] {
+            let invoice_id = client.create_invoice(&merchant, token_id, &1_000_000, &expiry, &None);
+            client.pay(&invoice_id, &payer, &1_000_000);
+            assert_eq!(client.get_payment(&invoice_id, &0).unwrap().fee, fee);
+        }
+    }
+
+    /// Pays a fresh invoice of `amount` and returns the fee source of its `paid` event
+    fn pay_fresh_invoice(
+        env: &Env,
+        client: &CheckoutContractClient,
+        merchant: &Address,
+        payer: &Address,
+        token: &Address,
+        amount: i128,
+    ) -> FeeSource {
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(merchant, token, &amount, &expiry, &None);
+        client.pay(&invoice_id, payer, &amount);
+        let (_, _, data) = env.events().all().last().unwrap();
+        let data: PaidEvent = data.into_val(env);
+        data.5
+    }
+
+    #[test]
+    fn test_fee_tiers_by_rolling_volume() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &1_000_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 300, FeeRefund::Returned)));
+        client.set_fee_tiers(&admin, &usdc_id, &vec![
+            &env,
+            FeeTier { min_volume: 50_000_000, bps: 200 },
+            FeeTier { min_volume: 100_000_000, bps: 100 },
+        ]);
+        
+        // No volume yet: base rate of 3%
+        let source = pay_fresh_invoice(&env, &client, &merchant, &payer, &usdc_id, 60_000_000);
+        assert_eq!(source, FeeSource::Base);
+        assert_eq!(usdc_client.balance(&collector), 1_800_000);
+        assert_eq!(client.get_merchant_volume(&merchant, &usdc_id), 60_000_000);
+        
+        // 60 USDC settled reaches the first tier
+        let source = pay_fresh_invoice(&env, &client, &merchant, &payer, &usdc_id, 50_000_000);
+        assert_eq!(source, FeeSource::Tier(0));
+        assert_eq!(usdc_client.balance(&collector), 2_800_000);
+        
+        // 110 USDC reaches the second
+        let source = pay_fresh_invoice(&env, &client, &merchant, &payer, &usdc_id, 10_000_000);
+        assert_eq!(source, FeeSource::Tier(1));
+        assert_eq!(usdc_client.balance(&collector), 2_900_000);
+        
+        // Volume older than 30 days drops out of the window
+        let start = env.ledger().timestamp();
+        env.ledger().with_mut(|li| li.timestamp = start + 29 * 86_400);
+        assert_eq!(client.get_merchant_volume(&merchant, &usdc_id), 120_000_000);
+        env.ledger().with_mut(|li| li.timestamp = start + 30 * 86_400);
+        assert_eq!(client.get_merchant_volume(&merchant, &usdc_id), 0);
+        let source = pay_fresh_invoice(&env, &client, &merchant, &payer, &usdc_id, 10_000_000);
+        assert_eq!(source, FeeSource::Base);
+        assert_eq!(client.get_merchant_volume(&merchant, &usdc_id), 10_000_000);
+    }
+
+    #[test]
+    fn test_fee_tiers_are_per_token() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let (eurc_id, _) = create_usdc(&env);
+        client.add_token(&admin, &eurc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &1_000_000_000);
+        token::StellarAssetClient::new(&env, &eurc_id).mint(&payer, &1_000_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 300, FeeRefund::Returned)));
+        client.set_fee_tiers(&admin, &usdc_id, &vec![&env, FeeTier { min_volume: 0, bps: 100 }]);
+        assert_eq!(client.get_fee_tiers(&eurc_id), vec![&env]);
+        
+        // USDC tiers do not apply to EURC payments
+        let source = pay_fresh_invoice(&env, &client, &merchant, &payer, &usdc_id, 10_000_000);
+        assert_eq!(source, FeeSource::Tier(0));
+        let source = pay_fresh_invoice(&env, &client, &merchant, &payer, &eurc_id, 10_000_000);
+        assert_eq!(source, FeeSource::Base);
+        
+        client.set_fee_tiers(&admin, &eurc_id, &vec![&env, FeeTier { min_volume: 0, bps: 150 }]);
+        let source = pay_fresh_invoice(&env, &client, &merchant, &payer, &eurc_id, 10_000_000);
+        assert_eq!(source, FeeSource::Tier(0));
+        assert_eq!(token::Client::new(&env, &eurc_id).balance(&collector), 450_000);
+    }
+
+    #[test]
+    fn test_refunds_do_not_count_towards_volume() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&merchant, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 300, FeeRefund::Returned)));
+        client.set_fee_tiers(&admin, &usdc_id, &vec![&env, FeeTier { min_volume: 50_000_000, bps: 100 }]);
+        
+        // The merchant pays its own large invoice and refunds it, fee included
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &60_000_000, &expiry, &None);
+        client.pay(&invoice_id, &merchant, &60_000_000);
+        assert_eq!(client.get_merchant_volume(&merchant, &usdc_id), 60_000_000);
+        client.refund(&invoice_id, &merchant, &20_000_000, &0);
+        assert_eq!(client.get_merchant_volume(&merchant, &usdc_id), 40_000_000);
+        client.refund(&invoice_id, &merchant, &40_000_000, &0);
+        assert_eq!(client.get_merchant_volume(&merchant, &usdc_id), 0);
+        
+        // Which leaves it at the base rate
+        let source = pay_fresh_invoice(&env, &client, &merchant, &merchant, &usdc_id, 10_000_000);
+        assert_eq!(source, FeeSource::Base);
+    }
+
+    #[test]
+    fn test_merchant_fee_override() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let other = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &1_000_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 300, FeeRefund::Returned)));
+        client.set_fee_tiers(&admin, &usdc_id, &vec![&env, FeeTier { min_volume: 0, bps: 250 }]);
+        
+        client.set_merchant_fee(&admin, &merchant, &Some(50));
+        assert_eq!(client.get_merchant_fee(&merchant), Some(50));
+        
+        let source = pay_fresh_invoice(&env, &client, &merchant, &payer, &usdc_id, 10_000_000);
+        assert_eq!(source, FeeSource::Override);
+        assert_eq!(usdc_client.balance(&collector), 50_000);
+        
+        // Other merchants still get the tier
+        let source = pay_fresh_invoice(&env, &client, &other, &payer, &usdc_id, 10_000_000);
+        assert_eq!(source, FeeSource::Tier(0));
+        assert_eq!(usdc_client.balance(&collector), 300_000);
+        
+        client.set_merchant_fee(&admin, &merchant, &None);
+        let source = pay_fresh_invoice(&env, &client, &merchant, &payer, &usdc_id, 10_000_000);
+        assert_eq!(source, FeeSource::Tier(0));
+        
+        assert_eq!(
+            client.try_set_merchant_fee(&admin, &merchant, &Some(10_001)),
+            Err(Ok(Error::InvalidFeeTier))
+        );
+        assert_eq!(
+            client.try_set_merchant_fee(&other, &merchant, &Some(0)),
+            Err(Ok(Error::UnauthorizedAccess))
+        );
+    }
+
+    #[test]
+    fn test_invalid_fee_tiers() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let outsider = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let tier = |min_volume: i128, bps: u32| FeeTier { min_volume, bps };
+        
+        let mut too_many = vec![&env];
+        for i in 0..=MAX_FEE_TIERS {
+            too_many.push_back(tier(i as i128, 100));
+        }
+        let cases = [
+            vec![&env, tier(100, 200), tier(100, 100)],
+            vec![&env, tier(100, 200), tier(50, 100)],
+            vec![&env, tier(-1, 200)],
+            vec![&env, tier(100, 10_001)],
+            too_many,
+        ];
+        for tiers in cases {
+            assert_eq!(client.try_set_fee_tiers(&admin, &usdc_id, &tiers), Err(Ok(Error::InvalidFeeTier)));
+        }
+        assert_eq!(
+            client.try_set_fee_tiers(&outsider, &usdc_id, &vec![&env]),
+            Err(Ok(Error::UnauthorizedAccess))
+        );
+        
+        let tiers = vec![&env, tier(0, 250), tier(1_000, 200)];
+        client.set_fee_tiers(&admin, &usdc_id, &tiers);
+        assert_eq!(client.get_fee_tiers(&usdc_id), tiers);
+    }
+
+    fn escrow_options(env: &Env, release_delay: u64) -> Option<InvoiceOptions> {
+        Some(InvoiceOptions { release_delay: Some(release_delay), ..InvoiceOptions::defaults(env) })
+    }
+
+    #[test]
+    fn test_escrow_released_by_payer() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Returned)));
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let options = escrow_options(&env, 86_400);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &options);
+        assert_eq!(client.get_escrow(&invoice_id), None);
+        
+        // Paying moves the whole amount into the contract
+        let paid_at = env.ledger().timestamp();
+        client.pay(&invoice_id, &payer, &10_000_000);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Escrowed));
+        assert_eq!(usdc_client.balance(&client.address), 10_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        assert_eq!(usdc_client.balance(&collector), 0);
+        assert_eq!(client.get_merchant_volume(&merchant, &usdc_id), 0);
+        assert_eq!(
+            client.get_escrow(&invoice_id),
+            Some(Escrow {
+                invoice_id: invoice_id.clone(),
+                payer: payer.clone(),
+                amount: 10_000_000,
+                fee: 100_000,
+                funded_at: paid_at,
+                release_at: paid_at + 86_400,
+            })
+        );
+        
+        // Only the payer can release before the delay has passed
+        assert_eq!(
+            client.try_release(&invoice_id, &merchant),
+            Err(Ok(Error::ReleaseNotDue))
+        );
+        client.release(&invoice_id, &payer);
+        
+        let (_, _, data) = env.events().all().last().unwrap();
+        let data: (BytesN<32>, Address, i128) = data.into_val(&env);
+        assert_eq!(data, (invoice_id.clone(), payer.clone(), 9_900_000));
+        
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Released));
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        assert_eq!(usdc_client.balance(&merchant), 9_900_000);
+        assert_eq!(usdc_client.balance(&collector), 100_000);
+        assert_eq!(client.get_merchant_volume(&merchant, &usdc_id), 10_000_000);
+        
+        assert_eq!(
+            client.try_release(&invoice_id, &payer),
+            Err(Ok(Error::EscrowNotHeld))
+        );
+    }
+
+    #[test]
+    fn test_escrow_released_after_delay() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let seller = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let keeper = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let mut options = split_options(&env, &[(&merchant, 8000), (&seller, 2000)]).unwrap();
+        options.release_delay = Some(3_600);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &Some(options));
+        client.pay(&invoice_id, &payer, &10_000_000);
+        let release_at = client.get_escrow(&invoice_id).unwrap().release_at;
+        
+        // Escrowed invoices are not refunded by expiry
+        env.ledger().with_mut(|li| li.timestamp = release_at - 1);
+        assert_eq!(client.expire_invoices(&vec![&env, invoice_id.clone()]), 0);
+        assert_eq!(
+            client.try_release(&invoice_id, &keeper),
+            Err(Ok(Error::ReleaseNotDue))
+        );
+        
+        // Anyone can release once the delay has passed, across the split table
+        env.ledger().with_mut(|li| li.timestamp = release_at);
+        client.release(&invoice_id, &keeper);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Released));
+        assert_eq!(usdc_client.balance(&merchant), 8_000_000);
+        assert_eq!(usdc_client.balance(&seller), 2_000_000);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+    }
+
+    #[test]
+    fn test_refund_escrowed_invoice() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Kept)));
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let options = escrow_options(&env, 86_400);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &options);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        
+        // The fee was never collected, so the payer gets everything back
+        // from the contract even though fees are normally kept
+        assert_eq!(
+            client.try_refund(&invoice_id, &merchant, &9_900_000, &0),
+            Err(Ok(Error::AmountMismatch))
+        );
+        client.refund(&invoice_id, &merchant, &10_000_000, &0);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Refunded));
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        assert_eq!(usdc_client.balance(&collector), 0);
+        assert_eq!(
+            client.try_release(&invoice_id, &payer),
+            Err(Ok(Error::EscrowNotHeld))
+        );
+        
+        // Released invoices are refunded like direct payments
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &options);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        client.release(&invoice_id, &payer);
+        client.refund(&invoice_id, &merchant, &9_900_000, &0);
+        assert_eq!(usdc_client.balance(&payer), 99_900_000);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        assert_eq!(usdc_client.balance(&collector), 100_000);
+    }
+
+    #[test]
+    fn test_invalid_escrow_options() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        let expiry = env.ledger().timestamp() + 600;
+        
+        for delay in [0, MAX_RELEASE_DELAY_SECS + 1] {
+            assert_eq!(
+                client.try_create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &escrow_options(&env, delay)),
+                Err(Ok(Error::InvalidEscrow))
+            );
+        }
+        
+        // Escrow cannot be combined with installments
+        let mut options = escrow_options(&env, 3_600).unwrap();
+        options.allow_partial = true;
+        assert_eq!(
+            client.try_create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &Some(options)),
+            Err(Ok(Error::InvalidEscrow))
+        );
+        
+        // Directly settled invoices have nothing to release
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        assert_eq!(
+            client.try_release(&invoice_id, &payer),
+            Err(Ok(Error::EscrowNotHeld))
+        );
+        assert_eq!(client.get_escrow(&invoice_id), None);
+    }
+
+    /// Creates an escrow invoice of 10 USDC releasing after a day, and pays it
+    fn escrowed_invoice(
+        env: &Env,
+        client: &CheckoutContractClient,
+        merchant: &Address,
+        payer: &Address,
+        token: &Address,
+    ) -> BytesN<32> {
+        let expiry = env.ledger().timestamp() + 600;
+        let options = escrow_options(env, 86_400);
+        let invoice_id = client.create_invoice(merchant, token, &10_000_000, &expiry, &options);
+        client.pay(&invoice_id, payer, &10_000_000);
+        invoice_id
+    }
+
+    #[test]
+    fn test_dispute_partial_split() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let arbiter = Address::generate(&env);
+        let outsider = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Returned)));
+        client.grant_role(&Role::Arbiter, &arbiter);
+        
+        let invoice_id = escrowed_invoice(&env, &client, &merchant, &payer, &usdc_id);
+        let opened_at = env.ledger().timestamp();
+        let deadline = client.open_dispute(&invoice_id, &payer, &3);
+        assert_eq!(deadline, opened_at + client.get_arbitration().resolution_secs);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Disputed));
+        
+        let (_, _, data) = env.events().all().last().unwrap();
+        let data: (BytesN<32>, Address, u32, u64) = data.into_val(&env);
+        assert_eq!(data, (invoice_id.clone(), payer.clone(), 3, deadline));
+        
+        // The funds are frozen, even for the payer
+        assert_eq!(
+            client.try_release(&invoice_id, &payer),
+            Err(Ok(Error::DisputePending))
+        );
+        assert_eq!(
+            client.try_vote_dispute(&invoice_id, &outsider, &0),
+            Err(Ok(Error::UnauthorizedAccess))
+        );
+        
+        // The admin must be granted the arbiter role to vote
+        assert!(!client.has_role(&Role::Arbiter, &admin));
+        assert_eq!(
+            client.try_vote_dispute(&invoice_id, &admin, &0),
+            Err(Ok(Error::UnauthorizedAccess))
+        );
+        
+        // Votes stop while payments are paused
+        client.pause(&admin, &Operation::Payments);
+        assert_eq!(
+            client.try_vote_dispute(&invoice_id, &arbiter, &4_000_000),
+            Err(Ok(Error::OperationPaused))
+        );
+        client.unpause(&admin, &Operation::Payments);
+        
+        // 4 USDC back to the payer; the merchant's 6 USDC bear 60% of the fee
+        assert!(client.vote_dispute(&invoice_id, &arbiter, &4_000_000));
+        
+        let (_, _, data) = env.events().all().last().unwrap();
+        let data: (BytesN<32>, i128, i128) = data.into_val(&env);
+        assert_eq!(data, (invoice_id.clone(), 4_000_000, 5_940_000));
+        
+        assert_eq!(usdc_client.balance(&payer), 94_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 5_940_000);
+        assert_eq!(usdc_client.balance(&collector), 60_000);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        assert_eq!(client.get_merchant_volume(&merchant, &usdc_id), 6_000_000);
+        
+        let invoice = client.get_invoice(&invoice_id).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Resolved);
+        assert_eq!(invoice.fee_total, 60_000);
+        let dispute = client.get_dispute(&invoice_id).unwrap();
+        assert_eq!(dispute.status, DisputeStatus::Resolved);
+        assert_eq!(dispute.reason, 3);
+        assert_eq!(dispute.payer_amount, 4_000_000);
+        assert_eq!(dispute.votes.len(), 1);
+        
+        // A resolved dispute takes no more votes and holds nothing to release
+        client.grant_role(&Role::Arbiter, &admin);
+        assert_eq!(
+            client.try_vote_dispute(&invoice_id, &admin, &0),
+            Err(Ok(Error::DisputeNotOpen))
+        );
+        assert_eq!(
+            client.try_release(&invoice_id, &payer),
+            Err(Ok(Error::EscrowNotHeld))
+        );
+    }
+
+    #[test]
+    fn test_dispute_full_outcomes() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let arbiter = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Returned)));
+        client.grant_role(&Role::Arbiter, &arbiter);
+        
+        // Everything back to the payer: no fee is collected
+        let invoice_id = escrowed_invoice(&env, &client, &merchant, &payer, &usdc_id);
+        client.open_dispute(&invoice_id, &payer, &1);
+        assert!(client.vote_dispute(&invoice_id, &arbiter, &10_000_000));
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        assert_eq!(usdc_client.balance(&collector), 0);
+        assert_eq!(client.get_merchant_volume(&merchant, &usdc_id), 0);
+        
+        // Everything to the merchant: settled as on release
+        let invoice_id = escrowed_invoice(&env, &client, &merchant, &payer, &usdc_id);
+        client.open_dispute(&invoice_id, &payer, &2);
+        assert!(client.vote_dispute(&invoice_id, &arbiter, &0));
+        assert_eq!(usdc_client.balance(&payer), 90_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 9_900_000);
+        assert_eq!(usdc_client.balance(&collector), 100_000);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Resolved));
+    }
+
+    #[test]
+    fn test_dispute_panel_threshold() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let arbiters = [Address::generate(&env), Address::generate(&env), Address::generate(&env)];
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        for arbiter in arbiters.iter() {
+            client.grant_role(&Role::Arbiter, arbiter);
+        }
+        
+        let config = ArbitrationConfig { threshold: 2, resolution_secs: 3_600 };
+        client.set_arbitration(&admin, &config);
+        assert_eq!(client.get_arbitration(), config);
+        
+        let invoice_id = escrowed_invoice(&env, &client, &merchant, &payer, &usdc_id);
+        client.open_dispute(&invoice_id, &payer, &1);
+        
+        // A later change does not affect the open dispute
+        client.set_arbitration(&admin, &ArbitrationConfig { threshold: 3, resolution_secs: 3_600 });
+        
+        // Votes only count towards the outcome they name
+        assert!(!client.vote_dispute(&invoice_id, &arbiters[0], &5_000_000));
+        assert!(!client.vote_dispute(&invoice_id, &arbiters[1], &3_000_000));
+        assert_eq!(
+            client.try_vote_dispute(&invoice_id, &arbiters[0], &3_000_000),
+            Err(Ok(Error::AlreadyVoted))
+        );
+        assert_eq!(usdc_client.balance(&client.address), 10_000_000);
+        
+        assert!(client.vote_dispute(&invoice_id, &arbiters[2], &3_000_000));
+        assert_eq!(usdc_client.balance(&payer), 93_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 7_000_000);
+        
+        let dispute = client.get_dispute(&invoice_id).unwrap();
+        assert_eq!(dispute.threshold, 2);
+        assert_eq!(dispute.votes.len(), 3);
+        assert_eq!(dispute.payer_amount, 3_000_000);
+    }
+
+    #[test]
+    fn test_dispute_lapses_to_release() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let arbiter = Address::generate(&env);
+        let keeper = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.grant_role(&Role::Arbiter, &arbiter);
+        client.set_arbitration(&admin, &ArbitrationConfig { threshold: 1, resolution_secs: 3_600 });
+        
+        let invoice_id = escrowed_invoice(&env, &client, &merchant, &payer, &usdc_id);
+        let deadline = client.open_dispute(&invoice_id, &payer, &1);
+        
+        env.ledger().with_mut(|li| li.timestamp = deadline - 1);
+        assert_eq!(
+            client.try_release(&invoice_id, &keeper),
+            Err(Ok(Error::DisputePending))
+        );
+        
+        // Undecided by the deadline, the dispute lapses and anyone can
+        // release, even before the escrow's own release time
+        env.ledger().with_mut(|li| li.timestamp = deadline);
+        assert_eq!(
+            client.try_vote_dispute(&invoice_id, &arbiter, &10_000_000),
+            Err(Ok(Error::DisputeDeadlinePassed))
+        );
+        assert!(deadline < client.get_escrow(&invoice_id).unwrap().release_at);
+        client.release(&invoice_id, &keeper);
+        
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Released));
+        assert_eq!(client.get_dispute(&invoice_id).unwrap().status, DisputeStatus::Lapsed);
+        assert_eq!(usdc_client.balance(&merchant), 10_000_000);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+    }
+
+    #[test]
+    fn test_dispute_validation() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let arbiter = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        client.grant_role(&Role::Arbiter, &arbiter);
+        
+        // Arbitration settings are bounded and admin-only
+        for (threshold, resolution_secs) in [(0, 3_600), (11, 3_600), (1, 0), (1, 7_776_001)] {
+            assert_eq!(
+                client.try_set_arbitration(&admin, &ArbitrationConfig { threshold, resolution_secs }),
+                Err(Ok(Error::InvalidArbitration))
+            );
+        }
+        assert_eq!(
+            client.try_set_arbitration(&arbiter, &ArbitrationConfig { threshold: 1, resolution_secs: 60 }),
+            Err(Ok(Error::UnauthorizedAccess))
+        );
+        
+        // Only escrowed invoices can be disputed, and only by their payer
+        let expiry = env.ledger().timestamp() + 600;
+        let direct_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&direct_id, &payer, &10_000_000);
+        assert_eq!(
+            client.try_open_dispute(&direct_id, &payer, &1),
+            Err(Ok(Error::EscrowNotHeld))
+        );
+        assert_eq!(
+            client.try_vote_dispute(&direct_id, &arbiter, &0),
+            Err(Ok(Error::DisputeNotFound))
+        );
+        
+        let invoice_id = escrowed_invoice(&env, &client, &merchant, &payer, &usdc_id);
+        assert_eq!(
+            client.try_open_dispute(&invoice_id, &merchant, &1),
+            Err(Ok(Error::Unauthorized))
+        );
+        
+        // Votes must stay within the escrowed amount
+        client.open_dispute(&invoice_id, &payer, &1);
+        for payer_amount in [-1, 10_000_001] {
+            assert_eq!(
+                client.try_vote_dispute(&invoice_id, &arbiter, &payer_amount),
+                Err(Ok(Error::InvalidResolution))
+            );
+        }
+        assert_eq!(
+            client.try_open_dispute(&invoice_id, &payer, &1),
+            Err(Ok(Error::EscrowNotHeld))
+        );
+        assert_eq!(
+            client.try_refund(&invoice_id, &merchant, &10_000_000, &0),
+            Err(Ok(Error::InvoiceNotPaid))
+        );
+        
+        // The window closes at the release time
+        let invoice_id = escrowed_invoice(&env, &client, &merchant, &payer, &usdc_id);
+        let release_at = client.get_escrow(&invoice_id).unwrap().release_at;
+        env.ledger().with_mut(|li| li.timestamp = release_at);
+        assert_eq!(
+            client.try_open_dispute(&invoice_id, &payer, &1),
+            Err(Ok(Error::DisputeWindowClosed))
+        );
+    }
+
+    #[test]
+    fn test_partial_refunds() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &50_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &50_000_000);
+        
+        // One damaged item out of five, then another
+        assert_eq!(client.refund(&invoice_id, &merchant, &10_000_000, &7), 0);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::PartiallyRefunded));
+        assert_eq!(client.refund(&invoice_id, &merchant, &10_000_000, &8), 1);
+        assert_eq!(usdc_client.balance(&payer), 70_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 30_000_000);
+        assert_eq!(client.get_invoice(&invoice_id).unwrap().refunded_total, 20_000_000);
+        
+        // Never more than what remains
+        for amount in [0, 30_000_001] {
+            assert_eq!(
+                client.try_refund(&invoice_id, &merchant, &amount, &7),
+                Err(Ok(Error::AmountMismatch))
+            );
+        }
+        
+        assert_eq!(client.refund(&invoice_id, &merchant, &30_000_000, &1), 2);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Refunded));
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        assert_eq!(
+            client.try_refund(&invoice_id, &merchant, &1, &1),
+            Err(Ok(Error::InvoiceNotPaid))
+        );
+        
+        let refunds = client.get_refunds(&invoice_id);
+        assert_eq!(refunds.len(), 3);
+        let refund = refunds.get(1).unwrap();
+        assert_eq!((refund.amount, refund.fee, refund.reason), (10_000_000, 0, 8));
+        assert_eq!(refund.invoice_id, invoice_id);
+    }
+
+    #[test]
+    fn test_partial_refunds_across_installments() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer_a = Address::generate(&env);
+        let payer_b = Address::generate(&env);
+        let (client, admin, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&payer_a, &7_000_000);
+        usdc_admin_client.mint(&payer_b, &3_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Returned)));
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &partial_options(&env));
+        client.pay(&invoice_id, &payer_a, &7_000_000);
+        client.pay(&invoice_id, &payer_b, &3_000_000);
+        
+        // Refunds continue through the payments oldest first, each
+        // payment's fee returned in proportion
+        client.refund(&invoice_id, &merchant, &5_000_000, &0);
+        assert_eq!(usdc_client.balance(&payer_a), 5_000_000);
+        assert_eq!(usdc_client.balance(&collector), 50_000);
+        
+        client.refund(&invoice_id, &merchant, &4_000_000, &0);
+        assert_eq!(usdc_client.balance(&payer_a), 7_000_000);
+        assert_eq!(usdc_client.balance(&payer_b), 2_000_000);
+        assert_eq!(usdc_client.balance(&collector), 10_000);
+        
+        client.refund(&invoice_id, &merchant, &1_000_000, &0);
+        assert_eq!(usdc_client.balance(&payer_b), 3_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        assert_eq!(usdc_client.balance(&collector), 0);
+        
+        let fees: soroban_sdk::Vec<i128> = client
+            .get_refunds(&invoice_id)
+            .iter()
+            .fold(vec![&env], |mut fees, refund| {
+                fees.push_back(refund.fee);
+                fees
+            });
+        assert_eq!(fees, vec![&env, 50_000, 40_000, 10_000]);
+        let invoice = client.get_invoice(&invoice_id).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Refunded);
+        assert_eq!((invoice.refunded_total, invoice.fees_refunded), (10_000_000, 100_000));
+    }
+
+    #[test]
+    fn test_partial_refunds_claw_back_splits() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let platform = Address::generate(&env);
+        let courier = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let options = split_options(&env, &[(&merchant, 5000), (&platform, 2500), (&courier, 2500)]);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &options);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        
+        // Refunding 3 stroops takes all of them from the merchant, so the
+        // fourth gives one back to it
+        client.refund(&invoice_id, &merchant, &3, &0);
+        assert_eq!(usdc_client.balance(&merchant), 4_999_997);
+        client.refund(&invoice_id, &merchant, &1, &0);
+        assert_eq!(usdc_client.balance(&merchant), 4_999_998);
+        assert_eq!(usdc_client.balance(&platform), 2_499_999);
+        assert_eq!(usdc_client.balance(&courier), 2_499_999);
+        
+        // Odd-sized refunds still claw back exactly what was paid out
+        for amount in [3_000_001, 6_999_995] {
+            client.refund(&invoice_id, &merchant, &amount, &0);
+        }
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        assert_eq!(usdc_client.balance(&platform), 0);
+        assert_eq!(usdc_client.balance(&courier), 0);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Refunded));
+    }
+
+    #[test]
+    fn test_refund_request_approved() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        
+        assert_eq!(client.request_refund(&invoice_id, &payer, &4_000_000, &5), 0);
+        let (_, _, data) = env.events().all().last().unwrap();
+        let data: (BytesN<32>, u32, Address, i128, u32) = data.into_val(&env);
+        assert_eq!(data, (invoice_id.clone(), 0, payer.clone(), 4_000_000, 5));
+        
+        let request = client.get_refund_request(&invoice_id, &0).unwrap();
+        assert_eq!(request.status, RefundRequestStatus::Pending);
+        assert_eq!((request.amount, request.reason), (4_000_000, 5));
+        assert_eq!(usdc_client.balance(&payer), 90_000_000);
+        
+        // Only the invoice's merchant decides
+        assert_eq!(
+            client.try_approve_refund(&invoice_id, &payer, &0),
+            Err(Ok(Error::Unauthorized))
+        );
+        
+        env.ledger().with_mut(|li| li.timestamp += 60);
+        assert_eq!(client.approve_refund(&invoice_id, &merchant, &0), 0);
+        assert_eq!(usdc_client.balance(&payer), 94_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 6_000_000);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::PartiallyRefunded));
+        assert_eq!(client.get_refunds(&invoice_id).get(0).unwrap().reason, 5);
+        
+        let request = client.get_refund_request(&invoice_id, &0).unwrap();
+        assert_eq!(request.status, RefundRequestStatus::Approved);
+        assert_eq!(request.decided_at, Some(env.ledger().timestamp()));
+        assert_eq!(request.refund_index, Some(0));
+        assert_eq!(
+            client.try_approve_refund(&invoice_id, &merchant, &0),
+            Err(Ok(Error::RequestNotPending))
+        );
+        
+        // Only one request may be pending at a time
+        client.request_refund(&invoice_id, &payer, &6_000_000, &5);
+        assert_eq!(
+            client.try_request_refund(&invoice_id, &payer, &1_000_000, &5),
+            Err(Ok(Error::RefundRequestPending))
+        );
+        
+        // The amount is checked again on approval
+        client.refund(&invoice_id, &merchant, &1_000_000, &0);
+        assert_eq!(
+            client.try_approve_refund(&invoice_id, &merchant, &1),
+            Err(Ok(Error::AmountMismatch))
+        );
+        client.reject_refund(&invoice_id, &merchant, &1, &0);
+        
+        // Once decided, a new request can cover what remains
+        assert_eq!(client.request_refund(&invoice_id, &payer, &5_000_000, &5), 2);
+        client.approve_refund(&invoice_id, &merchant, &2);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Refunded));
+        assert_eq!(
+            client.try_request_refund(&invoice_id, &payer, &1, &5),
+            Err(Ok(Error::InvoiceNotPaid))
+        );
+    }
+
+    #[test]
+    fn test_refund_request_rejected() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        token::StellarAssetClient::new(&env, &usdc_id).mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        client.request_refund(&invoice_id, &payer, &10_000_000, &2);
+        
+        client.reject_refund(&invoice_id, &merchant, &0, &9);
+        let (_, _, data) = env.events().all().last().unwrap();
+        let data: (BytesN<32>, u32, u32) = data.into_val(&env);
+        assert_eq!(data, (invoice_id.clone(), 0, 9));
+        
+        let request = client.get_refund_request(&invoice_id, &0).unwrap();
+        assert_eq!(request.status, RefundRequestStatus::Rejected);
+        assert_eq!(request.rejection_reason, Some(9));
+        assert_eq!(request.refund_index, None);
+        assert_eq!(usdc_client.balance(&merchant), 10_000_000);
+        assert_eq!(client.get_invoice_status(&invoice_id), Some(InvoiceStatus::Paid));
+        
+        assert_eq!(
+            client.try_reject_refund(&invoice_id, &merchant, &0, &9),
+            Err(Ok(Error::RequestNotPending))
+        );
+        assert_eq!(
+            client.try_approve_refund(&invoice_id, &merchant, &0),
+            Err(Ok(Error::RequestNotPending))
+        );
+        assert_eq!(
+            client.try_reject_refund(&invoice_id, &merchant, &1, &9),
+            Err(Ok(Error::RequestNotFound))
+        );
+        assert_eq!(client.get_refund_request(&invoice_id, &1), None);
+    }
+
+    #[test]
+    fn test_refund_request_window() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let other = Address::generate(&env);
+        let (client, _, usdc_id) = setup(&env);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&payer, &100_000_000);
+        usdc_admin_client.mint(&other, &100_000_000);
+        
+        assert_eq!(client.get_refund_window(&merchant), DEFAULT_REFUND_WINDOW_SECS);
+        assert_eq!(
+            client.try_set_refund_window(&merchant, &(MAX_REFUND_WINDOW_SECS + 1)),
+            Err(Ok(Error::InvalidPolicy))
+        );
+        client.set_refund_window(&merchant, &3_600);
+        assert_eq!(client.get_refund_window(&merchant), 3_600);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        assert_eq!(
+            client.try_request_refund(&invoice_id, &payer, &10_000_000, &1),
+            Err(Ok(Error::InvoiceNotPaid))
+        );
+        client.pay(&invoice_id, &payer, &10_000_000);
+        
+        // Only the payer, for at most the amount paid
+        assert_eq!(
+            client.try_request_refund(&invoice_id, &other, &10_000_000, &1),
+            Err(Ok(Error::PayerNotAllowed))
+        );
+        assert_eq!(
+            client.try_request_refund(&invoice_id, &payer, &10_000_001, &1),
+            Err(Ok(Error::AmountMismatch))
+        );
+        
+        // Refused once the window after payment has passed
+        let paid_at = env.ledger().timestamp();
+        env.ledger().with_mut(|li| li.timestamp = paid_at + 3_599);
+        client.request_refund(&invoice_id, &payer, &1_000_000, &1);
+        env.ledger().with_mut(|li| li.timestamp = paid_at + 3_600);
+        assert_eq!(
+            client.try_request_refund(&invoice_id, &payer, &1_000_000, &1),
+            Err(Ok(Error::RefundWindowClosed))
+        );
+        
+        // Invoices paid by several accounts cannot be requested
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &partial_options(&env));
+        client.pay(&invoice_id, &payer, &5_000_000);
+        client.pay(&invoice_id, &other, &5_000_000);
+        for account in [&payer, &other] {
+            assert_eq!(
+                client.try_request_refund(&invoice_id, account, &1_000_000, &1),
+                Err(Ok(Error::PayerNotAllowed))
+            );
+        }
+        
+        // A window of zero refuses every request
+        client.set_refund_window(&merchant, &0);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        assert_eq!(
+            client.try_request_refund(&invoice_id, &payer, &1_000_000, &1),
+            Err(Ok(Error::RefundWindowClosed))
+        );
+    }
+
+    /// Registers and allowlists a token whose issuer can freeze balances
+    fn create_freezable_token(env: &Env, client: &CheckoutContractClient) -> Address {
+        let token = env.register_stellar_asset_contract_v2(Address::generate(env));
+        token.issuer().set_flag(IssuerFlags::RevocableFlag);
+        client.add_token(&client.get_config().admin, &token.address());
+        token.address()
+    }
+
+    #[test]
+    fn test_refund_credited_when_payer_cannot_receive() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, admin, _) = setup(&env);
+        let usdc_id = create_freezable_token(&env, &client);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        
+        // The payer's balance is frozen, so the refund is held for them
+        usdc_admin_client.set_authorized(&payer, &false);
+        client.refund(&invoice_id, &merchant, &4_000_000, &0);
+        
+        let credited = env
+            .events()
+            .all()
+            .iter()
+            .find(|(_, topics, _)| {
+                topics.get(0).unwrap().shallow_eq(&symbol_short!("credited").to_val())
+            })
+            .unwrap();
+        let data: (BytesN<32>, Address, i128) = credited.2.into_val(&env);
+        assert_eq!(data, (invoice_id.clone(), usdc_id.clone(), 4_000_000));
+        
+        assert_eq!(usdc_client.balance(&payer), 90_000_000);
+        assert_eq!(usdc_client.balance(&merchant), 6_000_000);
+        assert_eq!(usdc_client.balance(&client.address), 4_000_000);
+        assert_eq!(client.get_claimable(&payer, &usdc_id), 4_000_000);
+        assert_eq!(client.get_claimable_total(&usdc_id), 4_000_000);
+        assert_eq!(client.get_invoice(&invoice_id).unwrap().refunded_total, 4_000_000);
+        
+        // Claiming fails until the payer can receive again, keeping the balance
+        assert!(client.try_claim_refund(&payer, &usdc_id).is_err());
+        assert_eq!(client.get_claimable(&payer, &usdc_id), 4_000_000);
+        
+        // Claims still go through while refunds are paused
+        usdc_admin_client.set_authorized(&payer, &true);
+        client.pause(&admin, &Operation::Refunds);
+        assert_eq!(client.claim_refund(&payer, &usdc_id), 4_000_000);
+        assert_eq!(usdc_client.balance(&payer), 94_000_000);
+        assert_eq!(usdc_client.balance(&client.address), 0);
+        assert_eq!(client.get_claimable(&payer, &usdc_id), 0);
+        assert_eq!(client.get_claimable_total(&usdc_id), 0);
+        assert_eq!(client.claim_refund(&payer, &usdc_id), 0);
+    }
+
+    #[test]
+    fn test_claimable_refunds_with_fees_and_installments() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let collector = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let other = Address::generate(&env);
+        let (client, admin, _) = setup(&env);
+        let usdc_id = create_freezable_token(&env, &client);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&payer, &100_000_000);
+        usdc_admin_client.mint(&other, &100_000_000);
+        client.set_fee_config(&admin, &Some(fee_config(&collector, 100, FeeRefund::Returned)));
+        
+        // A returned fee is held along with the net amount
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        usdc_admin_client.set_authorized(&payer, &false);
+        client.refund(&invoice_id, &merchant, &10_000_000, &0);
+        assert_eq!(usdc_client.balance(&merchant), 0);
+        assert_eq!(usdc_client.balance(&collector), 0);
+        assert_eq!(client.get_claimable(&payer, &usdc_id), 10_000_000);
+        
+        // Expiring installments credits the frozen payer without failing
+        // the refund to the other
+        usdc_admin_client.set_authorized(&payer, &true);
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &partial_options(&env));
+        client.pay(&invoice_id, &payer, &3_000_000);
+        client.pay(&invoice_id, &other, &2_000_000);
+        usdc_admin_client.set_authorized(&payer, &false);
+        env.ledger().with_mut(|li| li.timestamp = expiry + 1);
+        assert_eq!(client.expire_invoices(&vec![&env, invoice_id]), 1);
+        
+        assert_eq!(usdc_client.balance(&other), 100_000_000);
+        assert_eq!(client.get_claimable(&payer, &usdc_id), 13_000_000);
+        assert_eq!(client.get_claimable(&other, &usdc_id), 0);
+        assert_eq!(client.get_claimable_total(&usdc_id), 13_000_000);
+        assert_eq!(usdc_client.balance(&client.address), 13_000_000);
+        
+        usdc_admin_client.set_authorized(&payer, &true);
+        client.claim_refund(&payer, &usdc_id);
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+        assert_eq!(client.get_claimable_total(&usdc_id), 0);
+    }
+
+    #[test]
+    fn test_refund_fallback_authorization() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, _) = setup(&env);
+        let usdc_id = create_freezable_token(&env, &client);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &None);
+        client.pay(&invoice_id, &payer, &10_000_000);
+        usdc_admin_client.set_authorized(&payer, &false);
+        
+        let refund_args: soroban_sdk::Vec<soroban_sdk::Val> =
+            (invoice_id.clone(), merchant.clone(), 4_000_000i128, 0u32).into_val(&env);
+        let to_payer = MockAuthInvoke {
+            contract: &usdc_id,
+            fn_name: "transfer",
+            args: (merchant.clone(), payer.clone(), 4_000_000i128).into_val(&env),
+            sub_invokes: &[],
+        };
+        let to_contract = MockAuthInvoke {
+            contract: &usdc_id,
+            fn_name: "transfer",
+            args: (merchant.clone(), client.address.clone(), 4_000_000i128).into_val(&env),
+            sub_invokes: &[],
+        };
+        
+        // Authorizing only the transfer to the payer is not enough once the
+        // payer cannot receive
+        let to_payer_only = [to_payer.clone()];
+        env.mock_auths(&[MockAuth {
+            address: &merchant,
+            invoke: &MockAuthInvoke {
+                contract: &client.address,
+                fn_name: "refund",
+                args: refund_args.clone(),
+                sub_invokes: &to_payer_only,
+            },
+        }]);
+        assert!(client.try_refund(&invoice_id, &merchant, &4_000_000, &0).is_err());
+        assert_eq!(client.get_claimable(&payer, &usdc_id), 0);
+        
+        // The merchant authorizes the transfer into the contract instead
+        let with_fallback = [to_payer, to_contract];
+        env.mock_auths(&[MockAuth {
+            address: &merchant,
+            invoke: &MockAuthInvoke {
+                contract: &client.address,
+                fn_name: "refund",
+                args: refund_args.clone(),
+                sub_invokes: &with_fallback,
+            },
+        }]);
+        client.refund(&invoice_id, &merchant, &4_000_000, &0);
+        assert_eq!(
+            env.auths(),
+            [(
+                merchant.clone(),
+                AuthorizedInvocation {
+                    function: AuthorizedFunction::Contract((
+                        client.address.clone(),
+                        Symbol::new(&env, "refund"),
+                        refund_args,
+                    )),
+                    sub_invocations: [AuthorizedInvocation {
+                        function: AuthorizedFunction::Contract((
+                            usdc_id.clone(),
+                            Symbol::new(&env, "transfer"),
+                            (merchant.clone(), client.address.clone(), 4_000_000i128).into_val(&env),
+                        )),
+                        sub_invocations: [].into(),
+                    }]
+                    .into(),
+                },
+            )]
+        );
+        assert_eq!(client.get_claimable(&payer, &usdc_id), 4_000_000);
+    }
+
+    #[test]
+    fn test_refund_from_frozen_contract_not_credited() {
+        let env = Env::default();
+        env.mock_all_auths();
+        
+        let merchant = Address::generate(&env);
+        let payer = Address::generate(&env);
+        let (client, _, _) = setup(&env);
+        let usdc_id = create_freezable_token(&env, &client);
+        let usdc_client = token::Client::new(&env, &usdc_id);
+        let usdc_admin_client = token::StellarAssetClient::new(&env, &usdc_id);
+        usdc_admin_client.mint(&payer, &100_000_000);
+        
+        let expiry = env.ledger().timestamp() + 600;
+        let invoice_id = client.create_invoice(&merchant, &usdc_id, &10_000_000, &expiry, &partial_options(&env));
+        client.pay(&invoice_id, &payer, &3_000_000);
+        
+        // The contract's own balance is frozen: returning the installment
+        // fails instead of crediting a balance it cannot pay
+        usdc_admin_client.set_authorized(&client.address, &false);
+        env.ledger().with_mut(|li| li.timestamp = expiry + 1);
+        assert!(client.try_expire_invoices(&vec![&env, invoice_id.clone()]).is_err());
+        assert_eq!(client.get_claimable(&payer, &usdc_id), 0);
+        assert_eq!(client.get_claimable_total(&usdc_id), 0);
+        assert_eq!(usdc_client.balance(&client.address), 3_000_000);
+        
+        usdc_admin_client.set_authorized(&client.address, &true);
+        assert_eq!(client.expire_invoices(&vec![&env, invoice_id]), 1);
+        assert_eq!(usdc_client.balance(&payer), 100_000_000);
+    }
 }
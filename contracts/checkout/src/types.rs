@@ -1,4 +1,4 @@
-use soroban_sdk::{contracterror, contracttype, Address, BytesN, Bytes, Env};
+use soroban_sdk::{contracterror, contracttype, xdr::ToXdr, Address, BytesN, Bytes, Env, String, Vec};
 
 #[contracterror]
 #[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
@@ -10,13 +10,412 @@ pub enum Error {
     InvoiceNotFound = 4,
     InvalidAmount = 5,
     InvoiceExpired = 6,
-    InvoiceAlreadyPaid = 7,
     InvalidExpiry = 8,
     InvoiceNotOpen = 9,
     AmountMismatch = 10,
     Unauthorized = 11,
     InvoiceNotPaid = 12,
     PaymentNotFound = 13,
+    MigrationRequired = 14,
+    SchemaUpToDate = 15,
+    OperationPaused = 16,
+    InvalidRole = 17,
+    NoPendingAdmin = 18,
+    TokenNotAllowed = 19,
+    InvalidPolicy = 20,
+    InvalidReference = 21,
+    DuplicateInvoice = 22,
+    InvalidMetadataSize = 23,
+    InvalidLineItem = 24,
+    InvalidDiscount = 25,
+    ArithmeticOverflow = 26,
+    LinkNotFound = 27,
+    LinkInactive = 28,
+    InvalidUsageLimit = 29,
+    RevisionMismatch = 30,
+    InvalidAmendment = 31,
+    PayerNotAllowed = 32,
+    InvalidBatchSize = 33,
+    InvalidSplit = 34,
+    InvalidFeeConfig = 35,
+    InvalidFeeTier = 36,
+    InvalidEscrow = 37,
+    EscrowNotHeld = 38,
+    ReleaseNotDue = 39,
+    DisputeWindowClosed = 40,
+    DisputeNotFound = 41,
+    DisputeNotOpen = 42,
+    DisputePending = 43,
+    InvalidResolution = 44,
+    AlreadyVoted = 45,
+    InvalidArbitration = 46,
+    DisputeDeadlinePassed = 47,
+    RefundWindowClosed = 48,
+    RequestNotFound = 49,
+    RequestNotPending = 50,
+    RefundRequestPending = 51,
+}
+
+/// Storage layout version written by this build of the contract
+///
+/// Version 2 has not been released yet, so layout changes made before its
+/// release extend the version 1 to 2 mapping rather than adding a version.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Longest external reference accepted by `create_invoice_with_ref`
+pub const MAX_EXTERNAL_REF_LEN: u32 = 64;
+
+/// Longest `order_id` accepted in invoice metadata, in bytes
+pub const MAX_ORDER_ID_LEN: u32 = 64;
+/// Longest `description` accepted in invoice metadata, in bytes
+pub const MAX_DESCRIPTION_LEN: u32 = 140;
+/// Exact size of `items_hash` in invoice metadata, in bytes
+pub const ITEMS_HASH_LEN: u32 = 32;
+
+/// Most line items accepted on a single invoice
+pub const MAX_LINE_ITEMS: u32 = 20;
+/// Longest SKU accepted on a line item, in bytes
+pub const MAX_SKU_LEN: u32 = 32;
+/// Most invoices accepted by one `create_invoices` call
+///
+/// Each invoice is a separate ledger write, so this keeps a full batch (plus
+/// the merchant's ID counter) inside `TX_WRITE_ENTRIES`. CPU and memory use
+/// of a full batch is covered by `test_create_invoices_budget`.
+pub const MAX_BATCH_SIZE: u32 = 20;
+
+/// Ledger entries one transaction may write (network limit)
+///
+/// `pay_many` refuses carts whose payments could write more than this.
+pub const TX_WRITE_ENTRIES: u32 = 25;
+
+/// Stellar asset contract errors raised when the recipient of a transfer
+/// has no account (6), a deauthorized balance (11) or no trustline (13)
+pub const SAC_RECIPIENT_ERRORS: [u32; 3] = [6, 11, 13];
+
+/// Most recipients in an invoice's revenue split table
+pub const MAX_SPLITS: u32 = 5;
+
+/// Most volume tiers accepted by `set_fee_tiers`
+pub const MAX_FEE_TIERS: u32 = 10;
+/// Length of one bucket of merchant volume (one day)
+pub const VOLUME_BUCKET_SECS: u64 = 86_400;
+/// Number of daily buckets in the rolling volume window (30 days)
+pub const VOLUME_WINDOW_BUCKETS: u64 = 30;
+
+/// Longest release delay accepted for an escrow invoice (90 days)
+pub const MAX_RELEASE_DELAY_SECS: u64 = 7_776_000;
+
+/// Matching arbiter votes needed to resolve a dispute, until the admin
+/// configures arbitration
+pub const DEFAULT_ARBITRATION_THRESHOLD: u32 = 1;
+/// Largest vote threshold accepted by `set_arbitration`
+pub const MAX_ARBITRATION_THRESHOLD: u32 = 10;
+/// Time arbiters have to resolve a dispute, until the admin configures
+/// arbitration (7 days)
+pub const DEFAULT_RESOLUTION_SECS: u64 = 604_800;
+/// Longest resolution window accepted by `set_arbitration` (90 days)
+pub const MAX_RESOLUTION_SECS: u64 = 7_776_000;
+
+/// Time after payment during which payers can request a refund, for
+/// merchants without their own window (30 days)
+pub const DEFAULT_REFUND_WINDOW_SECS: u64 = 2_592_000;
+/// Longest refund window accepted by `set_refund_window` (365 days)
+pub const MAX_REFUND_WINDOW_SECS: u64 = 31_536_000;
+
+/// Basis points in 100%
+pub const BPS_DENOMINATOR: i128 = 10_000;
+
+/// Default minimum invoice amount, in the invoice token's smallest unit
+pub const DEFAULT_MIN_AMOUNT: i128 = 1;
+/// Default maximum invoice amount, in the invoice token's smallest unit
+pub const DEFAULT_MAX_AMOUNT: i128 = i128::MAX;
+/// Default minimum lifetime of an invoice (5 minutes)
+pub const DEFAULT_MIN_EXPIRY_SECS: u64 = 300;
+/// Default maximum lifetime of an invoice (60 minutes)
+pub const DEFAULT_MAX_EXPIRY_SECS: u64 = 3600;
+
+/// Limits that `create_invoice` enforces for a merchant
+///
+/// The amount bounds are denominated per token: they are compared with the
+/// invoice amount in the smallest unit of whichever token it is in, so one
+/// bound is worth different sums in tokens with different decimals or
+/// prices. A merchant invoicing in several tokens needs bounds that suit
+/// all of them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct CheckoutPolicy {
+    /// Smallest invoice amount accepted, in the invoice token's smallest unit
+    pub min_amount: i128,
+    /// Largest invoice amount accepted, in the invoice token's smallest unit
+    pub max_amount: i128,
+    /// Minimum seconds between invoice creation and expiry
+    pub min_expiry_secs: u64,
+    /// Maximum seconds between invoice creation and expiry
+    pub max_expiry_secs: u64,
+}
+
+impl CheckoutPolicy {
+    /// Checks that the bounds are positive and ordered
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.min_amount <= 0
+            || self.min_amount > self.max_amount
+            || self.min_expiry_secs == 0
+            || self.min_expiry_secs > self.max_expiry_secs
+        {
+            return Err(Error::InvalidPolicy);
+        }
+        Ok(())
+    }
+}
+
+/// Contract-wide configuration, written once by `initialize`
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Config {
+    /// Address allowed to perform administrative actions
+    pub admin: Address,
+    /// Token accepted at initialization; also assigned to invoices
+    /// migrated from schema version 1, which predate multi-token support
+    pub token: Address,
+    /// Policy for merchants without their own `MerchantPolicy` entry
+    pub default_policy: CheckoutPolicy,
+}
+
+/// What happens to the platform fee when a payment is refunded
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+#[repr(u32)]
+pub enum FeeRefund {
+    /// The collector returns the fee, co-signing the refund; the payer gets
+    /// the gross amount back. Also the mode of payments made while no fee
+    /// is configured, which carry no fee to return.
+    Returned = 0,
+    /// The collector keeps the fee; the payer gets the net amount back
+    Kept = 1,
+}
+
+/// Platform fee taken from every invoice payment
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct FeeConfig {
+    /// Fee in basis points of the gross amount, rounded down
+    pub bps: u32,
+    /// Address receiving the fees
+    pub collector: Address,
+    /// Whether refunds return the fee to the payer; recorded on each
+    /// payment, so a change only affects payments made afterwards
+    pub refund: FeeRefund,
+}
+
+impl FeeConfig {
+    /// Checks that the rate is at most 100%
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.bps as i128 > BPS_DENOMINATOR {
+            return Err(Error::InvalidFeeConfig);
+        }
+        Ok(())
+    }
+}
+
+/// Fee rate for merchants whose rolling volume in a token reaches
+/// `min_volume`
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct FeeTier {
+    /// Rolling 30-day settled volume, in the token's smallest unit, from
+    /// which the tier applies
+    pub min_volume: i128,
+    /// Fee in basis points, replacing `FeeConfig::bps`
+    pub bps: u32,
+}
+
+/// Which rate a platform fee was charged at
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum FeeSource {
+    /// No fee is configured
+    NoFee,
+    /// `FeeConfig::bps`, as the merchant's volume reaches no tier
+    Base,
+    /// The volume tier at this index of the tier table
+    Tier(u32),
+    /// The merchant's negotiated override
+    Override,
+}
+
+/// Settled volume of one merchant in one token during one day
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct VolumeBucket {
+    /// Day number (ledger timestamp divided by `VOLUME_BUCKET_SECS`)
+    pub day: u64,
+    /// Gross amount settled that day, less what has since been refunded
+    pub volume: i128,
+}
+
+/// Optional reconciliation data attached to an invoice
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct InvoiceMetadata {
+    /// Merchant's external order ID (at most `MAX_ORDER_ID_LEN` bytes)
+    pub order_id: Option<String>,
+    /// Short human-readable description (at most `MAX_DESCRIPTION_LEN` bytes)
+    pub description: Option<String>,
+    /// 32-byte hash committing to the off-chain line items (held as `Bytes`
+    /// because SDK 21 cannot convert `Option<BytesN<_>>` fields in tests)
+    pub items_hash: Option<Bytes>,
+}
+
+impl InvoiceMetadata {
+    /// Metadata with every field unset
+    pub fn empty() -> Self {
+        InvoiceMetadata {
+            order_id: None,
+            description: None,
+            items_hash: None,
+        }
+    }
+    
+    /// Checks every field against its size limit
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(order_id) = &self.order_id {
+            if order_id.len() > MAX_ORDER_ID_LEN {
+                return Err(Error::InvalidMetadataSize);
+            }
+        }
+        if let Some(description) = &self.description {
+            if description.len() > MAX_DESCRIPTION_LEN {
+                return Err(Error::InvalidMetadataSize);
+            }
+        }
+        if let Some(items_hash) = &self.items_hash {
+            if items_hash.len() != ITEMS_HASH_LEN {
+                return Err(Error::InvalidMetadataSize);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A single priced line on an itemized invoice
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct LineItem {
+    /// Merchant's stock keeping unit (at most `MAX_SKU_LEN` bytes)
+    pub sku: String,
+    /// Number of units
+    pub quantity: u32,
+    /// Price per unit in the invoice token's smallest unit, before tax
+    pub unit_price: i128,
+    /// Tax rate in basis points (0-10000)
+    pub tax_bps: u32,
+}
+
+/// Invoice-level discount, applied before tax
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum Discount {
+    /// Percentage of the subtotal in basis points
+    Percent(u32),
+    /// Fixed amount in the invoice token's smallest unit
+    Fixed(i128),
+}
+
+/// How an invoice total was derived
+///
+/// Invoices created with a plain amount have no items, no tax and no
+/// discount, so `subtotal == total == amount`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct InvoiceBreakdown {
+    /// Priced lines (empty for plain invoices)
+    pub items: Vec<LineItem>,
+    /// Sum of quantity * unit_price over all items
+    pub subtotal: i128,
+    /// Discount taken off the subtotal
+    pub discount: i128,
+    /// Tax charged on the discounted lines
+    pub tax: i128,
+    /// Amount due: subtotal - discount + tax
+    pub total: i128,
+}
+
+impl InvoiceBreakdown {
+    /// Breakdown of an invoice created with a plain amount
+    pub fn flat(env: &Env, amount: i128) -> Self {
+        InvoiceBreakdown {
+            items: Vec::new(env),
+            subtotal: amount,
+            discount: 0,
+            tax: 0,
+            total: amount,
+        }
+    }
+}
+
+/// One recipient's share of an invoice's revenue
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct SplitShare {
+    /// Address receiving the share
+    pub recipient: Address,
+    /// Share of each payment in basis points (all shares add up to 10000)
+    pub bps: u32,
+}
+
+/// How the payable amount of an invoice is determined
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum AmountMode {
+    /// The payer must pay exactly `amount`
+    Fixed,
+    /// The payer chooses any amount between the two bounds (inclusive);
+    /// `amount` is zero until the invoice is paid
+    Range(i128, i128),
+}
+
+/// Optional settings for a new invoice
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct InvoiceOptions {
+    /// Reconciliation data: order ID, description and line-item hash
+    pub metadata: InvoiceMetadata,
+    /// Accept several payments that together reach the amount
+    pub allow_partial: bool,
+    /// Only this account may pay the invoice (`None` for anyone)
+    pub designated_payer: Option<Address>,
+    /// Revenue split table (empty to pay the merchant in full)
+    pub splits: Vec<SplitShare>,
+    /// Hold the payment in escrow, releasing it this many seconds after
+    /// payment unless the payer confirms sooner (`None` to settle at once)
+    pub release_delay: Option<u64>,
+}
+
+impl InvoiceOptions {
+    /// Options used when a creation call passes `None`
+    pub fn defaults(env: &Env) -> Self {
+        InvoiceOptions {
+            metadata: InvoiceMetadata::empty(),
+            allow_partial: false,
+            designated_payer: None,
+            splits: Vec::new(env),
+            release_delay: None,
+        }
+    }
+}
+
+/// One invoice in a `create_invoices` batch
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct InvoiceSpec {
+    /// Allowlisted token contract the invoice is payable in
+    pub token: Address,
+    /// Amount in the token's smallest unit
+    pub amount: i128,
+    /// Unix timestamp when the invoice expires
+    pub expiry: u64,
+    /// Metadata, installment setting and designated payer
+    pub options: InvoiceOptions,
 }
 
 /// Invoice represents a payment request from a merchant
@@ -27,7 +426,10 @@ pub struct Invoice {
     pub id: BytesN<32>,
     /// Address of the merchant requesting payment
     pub merchant: Address,
-    /// Amount in USDC stroops (7 decimals)
+    /// SEP-41 token contract the invoice is denominated in
+    pub token: Address,
+    /// Amount in the token's smallest unit (for open-amount invoices, the
+    /// amount actually paid)
     pub amount: i128,
     /// Unix timestamp when the invoice expires
     pub expiry: u64,
@@ -35,24 +437,287 @@ pub struct Invoice {
     pub status: InvoiceStatus,
     /// Unix timestamp when the invoice was created
     pub created_at: u64,
-    /// Address of the payer (set after payment)
+    /// Unix timestamp of the last status change
+    pub updated_at: u64,
+    /// Address of the payer (set after payment; the latest payer for
+    /// invoices paid in installments)
     pub payer: Option<Address>,
+    /// Reconciliation data supplied by the merchant
+    pub metadata: InvoiceMetadata,
+    /// Line items, tax and discount behind `amount`
+    pub breakdown: InvoiceBreakdown,
+    /// Whether several payments may add up to `amount`
+    pub allow_partial: bool,
+    /// Sum of all payments received so far
+    pub amount_paid: i128,
+    /// Number of payment records stored under `DataKey::Payment`
+    pub payment_count: u32,
+    /// Fixed amount, or the range a payer may choose from
+    pub amount_mode: AmountMode,
+    /// Incremented by each `amend_invoice`, starting at 0
+    pub revision: u32,
+    /// Only this account may pay the invoice (`None` for anyone)
+    pub designated_payer: Option<Address>,
+    /// Recipients sharing each payment (empty to pay the merchant in full)
+    pub splits: Vec<SplitShare>,
+    /// Platform fees deducted from the payments received so far
+    pub fee_total: i128,
+    /// Address the fees were paid to (set by each payment carrying a fee)
+    pub fee_collector: Option<Address>,
+    /// Unix timestamp when the payment was passed on to the merchant and
+    /// counted towards its volume (`None` until then)
+    pub settled_at: Option<u64>,
+    /// Escrow release delay in seconds (`None` for direct settlement)
+    pub release_delay: Option<u64>,
+    /// Sum of all refunds returned to payers so far
+    pub refunded_total: i128,
+    /// Part of `refunded_total` returned by the fee collector
+    pub fees_refunded: i128,
+    /// Number of refund records stored under `DataKey::Refund`
+    pub refund_count: u32,
+    /// Number of refund requests stored under `DataKey::RefundRequest`
+    pub request_count: u32,
+}
+
+impl Invoice {
+    /// Status as seen at `now`: an open or partially paid invoice past its
+    /// expiry reads as `Expired` even before `expire_invoices` persists it
+    pub fn status_at(&self, now: u64) -> InvoiceStatus {
+        if self.is_awaiting_payment() && now > self.expiry {
+            InvoiceStatus::Expired
+        } else {
+            self.status
+        }
+    }
+    
+    /// Whether the stored status still accepts payments
+    pub fn is_awaiting_payment(&self) -> bool {
+        self.status == InvoiceStatus::Open || self.status == InvoiceStatus::PartiallyPaid
+    }
+}
+
+/// PaymentLink is a reusable, fixed-amount payment request
+/// 
+/// Unlike an invoice it stays payable after each payment until it is
+/// deactivated, reaches `max_uses`, or passes `end_date`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PaymentLink {
+    /// Unique identifier for the link
+    pub id: BytesN<32>,
+    /// Address of the merchant receiving payments
+    pub merchant: Address,
+    /// SEP-41 token contract the link is denominated in
+    pub token: Address,
+    /// Amount charged per use, in the token's smallest unit
+    pub amount: i128,
+    /// Most payments the link accepts (`None` for unlimited)
+    pub max_uses: Option<u32>,
+    /// Unix timestamp after which the link stops accepting payments
+    pub end_date: Option<u64>,
+    /// Number of payments received, also the next payment index
+    pub use_count: u32,
+    /// Cleared when the merchant deactivates the link
+    pub active: bool,
+    /// Unix timestamp when the link was created
+    pub created_at: u64,
+    /// Reconciliation data supplied by the merchant
+    pub metadata: InvoiceMetadata,
+}
+
+impl PaymentLink {
+    /// Whether the link accepts a payment at `now`
+    pub fn is_usable_at(&self, now: u64) -> bool {
+        self.active
+            && self.max_uses.map_or(true, |max| self.use_count < max)
+            && self.end_date.map_or(true, |end| now <= end)
+    }
+}
+
+/// Funds of an escrow invoice held by the contract
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Escrow {
+    /// ID of the escrow invoice
+    pub invoice_id: BytesN<32>,
+    /// Address that paid the invoice and may confirm the release
+    pub payer: Address,
+    /// Gross amount held, including the platform fee
+    pub amount: i128,
+    /// Platform fee collected from `amount` on release
+    pub fee: i128,
+    /// Unix timestamp when the funds were received
+    pub funded_at: u64,
+    /// Unix timestamp from which anyone may release the funds
+    pub release_at: u64,
+}
+
+/// How escrow disputes are decided
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct ArbitrationConfig {
+    /// Arbiters that must vote for the same outcome to resolve a dispute
+    /// (1 for a single arbiter)
+    pub threshold: u32,
+    /// Seconds after a dispute is opened during which arbiters can vote
+    pub resolution_secs: u64,
+}
+
+impl ArbitrationConfig {
+    /// Settings used until the admin calls `set_arbitration`
+    pub fn defaults() -> Self {
+        ArbitrationConfig {
+            threshold: DEFAULT_ARBITRATION_THRESHOLD,
+            resolution_secs: DEFAULT_RESOLUTION_SECS,
+        }
+    }
+    
+    /// Checks that the threshold and window are within bounds
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.threshold == 0
+            || self.threshold > MAX_ARBITRATION_THRESHOLD
+            || self.resolution_secs == 0
+            || self.resolution_secs > MAX_RESOLUTION_SECS
+        {
+            return Err(Error::InvalidArbitration);
+        }
+        Ok(())
+    }
+}
+
+/// One arbiter's proposed outcome of a dispute
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct DisputeVote {
+    /// Arbiter who voted
+    pub arbiter: Address,
+    /// Part of the escrowed amount to return to the payer; the rest goes
+    /// to the merchant
+    pub payer_amount: i128,
+}
+
+/// A payer's challenge of an escrow invoice, freezing its funds
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Dispute {
+    /// ID of the disputed escrow invoice
+    pub invoice_id: BytesN<32>,
+    /// Payer who opened the dispute
+    pub payer: Address,
+    /// Reason code chosen by the payer
+    pub reason: u32,
+    /// Current state of the dispute
+    pub status: DisputeStatus,
+    /// Unix timestamp when the dispute was opened
+    pub opened_at: u64,
+    /// Unix timestamp from which no more votes are accepted
+    pub deadline: u64,
+    /// Matching votes needed, fixed when the dispute is opened
+    pub threshold: u32,
+    /// Votes cast so far, in order
+    pub votes: Vec<DisputeVote>,
+    /// Amount returned to the payer (set once resolved)
+    pub payer_amount: i128,
+}
+
+/// Status of an escrow dispute
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+#[repr(u32)]
+pub enum DisputeStatus {
+    /// Funds are frozen while arbiters vote
+    Open = 0,
+    /// Enough arbiters agreed and the funds were divided
+    Resolved = 1,
+    /// The deadline passed without a decision and the funds were released
+    Lapsed = 2,
 }
 
 /// Payment represents a completed payment for an invoice
 #[derive(Clone, Debug, Eq, PartialEq)]
 #[contracttype]
 pub struct Payment {
-    /// ID of the invoice being paid
+    /// ID of the invoice (or payment link) being paid
     pub invoice_id: BytesN<32>,
     /// Address of the account that made the payment
     pub payer: Address,
-    /// Amount paid in USDC stroops
+    /// Gross amount paid in the invoice token's smallest unit
     pub amount: i128,
+    /// Platform fee deducted from `amount`
+    pub fee: i128,
+    /// Amount credited to the merchant (`amount - fee`)
+    pub net: i128,
+    /// Whether refunds of this payment return `fee`, as configured when
+    /// the payment was made
+    pub fee_refund: FeeRefund,
     /// Unix timestamp when the payment was made
     pub timestamp: u64,
 }
 
+impl Payment {
+    /// Most a refund can return of this payment: the gross amount when its
+    /// fee is returned, the net amount when the collector keeps it
+    pub fn refundable(&self) -> i128 {
+        match self.fee_refund {
+            FeeRefund::Returned => self.amount,
+            FeeRefund::Kept => self.net,
+        }
+    }
+}
+
+/// Refund represents money returned to the payers of an invoice
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Refund {
+    /// ID of the refunded invoice
+    pub invoice_id: BytesN<32>,
+    /// Amount returned to the payers, including `fee`
+    pub amount: i128,
+    /// Part of `amount` returned by the fee collector
+    pub fee: i128,
+    /// Reason code chosen by the merchant
+    pub reason: u32,
+    /// Unix timestamp when the refund was made
+    pub timestamp: u64,
+}
+
+/// A payer's request for a refund, awaiting the merchant's decision
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct RefundRequest {
+    /// ID of the invoice to refund
+    pub invoice_id: BytesN<32>,
+    /// Payer asking for the refund
+    pub payer: Address,
+    /// Amount requested
+    pub amount: i128,
+    /// Reason code chosen by the payer, copied to the refund record
+    pub reason: u32,
+    /// Current state of the request
+    pub status: RefundRequestStatus,
+    /// Unix timestamp when the request was made
+    pub requested_at: u64,
+    /// Unix timestamp of the merchant's decision
+    pub decided_at: Option<u64>,
+    /// Index of the resulting refund record, once approved
+    pub refund_index: Option<u32>,
+    /// Reason code given by the merchant, once rejected
+    pub rejection_reason: Option<u32>,
+}
+
+/// Status of a refund request
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+#[repr(u32)]
+pub enum RefundRequestStatus {
+    /// Awaiting the merchant's decision
+    Pending = 0,
+    /// The merchant approved and the refund was made
+    Approved = 1,
+    /// The merchant turned the request down
+    Rejected = 2,
+}
+
 /// Status of an invoice in the system
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[contracttype]
@@ -62,22 +727,115 @@ pub enum InvoiceStatus {
     Open = 0,
     /// Invoice has been paid
     Paid = 1,
-    /// Invoice has been refunded
+    /// Invoice has been refunded in full
     Refunded = 2,
     /// Invoice has expired without payment
     Expired = 3,
+    /// Invoice has received part of its amount in installments
+    PartiallyPaid = 4,
+    /// Invoice was voided by the merchant before payment
+    Cancelled = 5,
+    /// Invoice has been paid and the funds are held in escrow
+    Escrowed = 6,
+    /// Escrowed funds have been released to the merchant
+    Released = 7,
+    /// The payer has disputed the escrow and its funds are frozen
+    Disputed = 8,
+    /// Arbiters divided the escrowed funds between payer and merchant
+    Resolved = 9,
+    /// Part of the amount paid has been refunded
+    PartiallyRefunded = 10,
+}
+
+/// Classes of operations that the admin can pause independently
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+#[repr(u32)]
+pub enum Operation {
+    /// Creating new invoices
+    Invoicing = 0,
+    /// Paying invoices and settling escrowed funds
+    Payments = 1,
+    /// Refunding paid invoices
+    Refunds = 2,
+}
+
+/// Roles that gate administrative entrypoints
+///
+/// `Admin` is always the single address stored in `Config`; the other
+/// roles can be held by any number of accounts and, except for `Arbiter`,
+/// are implied for the admin.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+#[repr(u32)]
+pub enum Role {
+    /// Full control, including granting roles and upgrades
+    Admin = 0,
+    /// Can pause and unpause operation classes
+    Operator = 1,
+    /// Can manage fee parameters
+    FeeManager = 2,
+    /// Can manage the token allowlist and merchant checkout policies
+    Compliance = 3,
+    /// Can vote on escrow disputes (never implied for the admin)
+    Arbiter = 4,
 }
 
 /// Keys for contract data storage
 #[derive(Clone)]
 #[contracttype]
 pub enum DataKey {
+    /// Contract configuration (instance storage)
+    Config,
+    /// Storage layout version (instance storage, absent means 1)
+    SchemaVersion,
+    /// Maps operation class -> paused flag (instance storage)
+    Paused(Operation),
+    /// Admin proposed by `propose_admin`, awaiting acceptance (instance storage)
+    PendingAdmin,
+    /// Maps (role, account) -> membership flag
+    Role(Role, Address),
+    /// Maps token contract -> allowlist flag
+    AllowedToken(Address),
+    /// Maps merchant -> CheckoutPolicy overriding the default
+    MerchantPolicy(Address),
     /// Maps invoice_id -> Invoice
     Invoice(BytesN<32>),
-    /// Maps invoice_id -> Payment
-    Payment(BytesN<32>),
-    /// Global counter for generating invoice IDs
-    InvoiceCounter,
+    /// Maps (invoice_id, index) -> Payment, indices 0..payment_count
+    Payment(BytesN<32>, u32),
+    /// Maps (invoice_id, index) -> Refund, indices 0..refund_count
+    Refund(BytesN<32>, u32),
+    /// Maps (invoice_id, index) -> RefundRequest, indices 0..request_count
+    RefundRequest(BytesN<32>, u32),
+    /// Maps merchant -> refund request window in seconds
+    RefundWindow(Address),
+    /// Maps (payer, token) -> refunds held by the contract for the payer
+    Claimable(Address, Address),
+    /// Maps token -> sum of all claimable balances in that token
+    ClaimableTotal(Address),
+    /// Maps merchant -> counter for generating that merchant's invoice IDs
+    InvoiceCounter(Address),
+    /// Maps link_id -> PaymentLink
+    PaymentLink(BytesN<32>),
+    /// Maps (link_id, index) -> Payment, indices 0..use_count
+    LinkPayment(BytesN<32>, u32),
+    /// Platform fee settings, absent when no fee is charged (instance storage)
+    FeeConfig,
+    /// Maps token -> volume-based fee tiers, ascending by `min_volume`
+    FeeTiers(Address),
+    /// Maps token -> smallest fee per invoice or link payment, in the
+    /// token's smallest unit
+    MinFee(Address),
+    /// Maps merchant -> fee rate in basis points overriding the tiers
+    MerchantFeeOverride(Address),
+    /// Maps (merchant, token) -> daily VolumeBuckets of the rolling window
+    MerchantVolume(Address, Address),
+    /// Maps invoice_id -> Escrow, for escrow invoices once paid
+    Escrow(BytesN<32>),
+    /// Dispute settings, absent until configured (instance storage)
+    ArbitrationConfig,
+    /// Maps invoice_id -> Dispute, for disputed escrow invoices
+    Dispute(BytesN<32>),
 }
 
 /// Generates a unique invoice ID based on merchant address and current ledger info
@@ -92,8 +850,8 @@ pub fn generate_invoice_id(env: &Env, merchant: &Address) -> BytesN<32> {
     let timestamp = env.ledger().timestamp();
     let sequence = env.ledger().sequence();
     
-    // Get or increment a global counter for uniqueness
-    let counter_key = DataKey::InvoiceCounter;
+    // Get or increment the merchant's counter for uniqueness
+    let counter_key = DataKey::InvoiceCounter(merchant.clone());
     let counter: u64 = env.storage()
         .persistent()
         .get(&counter_key)
@@ -103,12 +861,55 @@ pub fn generate_invoice_id(env: &Env, merchant: &Address) -> BytesN<32> {
         .persistent()
         .set(&counter_key, &(counter + 1));
     
-    // Create a buffer with timestamp, sequence, and counter
-    let mut buffer = Bytes::new(env);
+    // Create a buffer with merchant, timestamp, sequence, and counter
+    let mut buffer = merchant.clone().to_xdr(env);
     buffer.extend_from_array(&timestamp.to_be_bytes());
     buffer.extend_from_array(&sequence.to_be_bytes());
     buffer.extend_from_array(&counter.to_be_bytes());
     
     // Hash the combined data to create a unique ID
-    env.crypto().sha256(&buffer)
-}
\ No newline at end of file
+    env.crypto().sha256(&buffer).into()
+}
+
+/// Generates the ID shared by the invoices settled in one `pay_many` call
+///
+/// # Arguments
+/// * `env` - The Soroban environment
+/// * `payer` - The payer address
+/// * `items` - The (invoice ID, amount) pairs being paid
+///
+/// # Returns
+/// A 32-byte cart ID
+pub fn generate_cart_id(
+    env: &Env,
+    payer: &Address,
+    items: &Vec<(BytesN<32>, i128)>,
+) -> BytesN<32> {
+    let mut buffer = payer.clone().to_xdr(env);
+    buffer.append(&items.clone().to_xdr(env));
+    buffer.extend_from_array(&env.ledger().timestamp().to_be_bytes());
+    buffer.extend_from_array(&env.ledger().sequence().to_be_bytes());
+    
+    env.crypto().sha256(&buffer).into()
+}
+
+/// Derives the invoice ID for a merchant's external reference
+///
+/// The ID is `sha256(xdr(contract) || xdr(merchant) || external_ref)`, where
+/// `xdr(..)` is the XDR encoding of the address as an `ScVal`, so it can be
+/// computed off-chain before the invoice exists.
+///
+/// # Arguments
+/// * `env` - The Soroban environment
+/// * `merchant` - The merchant address
+/// * `external_ref` - The merchant's own reference, e.g. an order number
+///
+/// # Returns
+/// A deterministic 32-byte invoice ID
+pub fn derive_invoice_id(env: &Env, merchant: &Address, external_ref: &Bytes) -> BytesN<32> {
+    let mut buffer = env.current_contract_address().to_xdr(env);
+    buffer.append(&merchant.clone().to_xdr(env));
+    buffer.append(external_ref);
+    
+    env.crypto().sha256(&buffer).into()
+}
@@ -4,6 +4,7 @@
 SECRET_KEY=${1:-$STELLAR_SECRET_KEY}
 MERCHANT_ADDRESS=${2:-"GBACG2GWKRAP2YRVGJFTAX2IVUFLS74GH5WT7YDWLAOOZI6LWNVYRSIM"}
 AMOUNT=${3:-1000000} # 1 USDC (7 decimals)
+TOKEN_CONTRACT_ID=${4:-$USDC_CONTRACT_ID}
 
 # Current time + 10 minutes (600 seconds) for expiry
 EXPIRY=$(date -u -v+10M +%s)
@@ -14,6 +15,12 @@ if [ -z "$SECRET_KEY" ]; then
   exit 1
 fi
 
+# Check if TOKEN_CONTRACT_ID is provided
+if [ -z "$TOKEN_CONTRACT_ID" ]; then
+  echo "Error: No token contract ID provided. Either pass it as the fourth argument or set the USDC_CONTRACT_ID environment variable."
+  exit 1
+fi
+
 # Read the checkout contract ID from the file
 CHECKOUT_CONTRACT_ID=$(cat /Users/faithrounds/CascadeProjects/minty/contracts/checkout/contract_id.txt)
 
@@ -24,7 +31,7 @@ if [ -z "$CHECKOUT_CONTRACT_ID" ]; then
 fi
 
 # Create an invoice
-echo "Creating invoice with merchant: $MERCHANT_ADDRESS, amount: $AMOUNT, expiry: $EXPIRY"
+echo "Creating invoice with merchant: $MERCHANT_ADDRESS, token: $TOKEN_CONTRACT_ID, amount: $AMOUNT, expiry: $EXPIRY"
 INVOICE_ID=$(stellar contract invoke \
   --id "$CHECKOUT_CONTRACT_ID" \
   --source-account "$SECRET_KEY" \
@@ -33,6 +40,7 @@ INVOICE_ID=$(stellar contract invoke \
   -- \
   create_invoice \
   --merchant "$MERCHANT_ADDRESS" \
+  --token "$TOKEN_CONTRACT_ID" \
   --amount "$AMOUNT" \
   --expiry "$EXPIRY" \
   --auth "$MERCHANT_ADDRESS")
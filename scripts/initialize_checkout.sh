@@ -3,6 +3,7 @@
 # Set your private key here or use an environment variable
 SECRET_KEY=${1:-$STELLAR_SECRET_KEY}
 USDC_CONTRACT_ID=${2:-$USDC_CONTRACT_ID}
+ADMIN_ADDRESS=${3:-$ADMIN_PUBLIC_KEY}
 
 # Check if SECRET_KEY is provided
 if [ -z "$SECRET_KEY" ]; then
@@ -16,6 +17,12 @@ if [ -z "$USDC_CONTRACT_ID" ]; then
   exit 1
 fi
 
+# Check if ADMIN_ADDRESS is provided
+if [ -z "$ADMIN_ADDRESS" ]; then
+  echo "Error: No admin address provided. Either pass it as the third argument or set the ADMIN_PUBLIC_KEY environment variable."
+  exit 1
+fi
+
 # Read the checkout contract ID from the file
 CHECKOUT_CONTRACT_ID=$(cat /Users/faithrounds/CascadeProjects/minty/contracts/checkout/contract_id.txt)
 
@@ -25,7 +32,7 @@ if [ -z "$CHECKOUT_CONTRACT_ID" ]; then
   exit 1
 fi
 
-# Initialize the contract with USDC token address
+# Initialize the contract with admin and USDC token address
 echo "Initializing checkout contract..."
 RESULT=$(stellar contract invoke \
   --id "$CHECKOUT_CONTRACT_ID" \
@@ -34,6 +41,7 @@ RESULT=$(stellar contract invoke \
   --network-passphrase 'Test SDF Network ; September 2015' \
   -- \
   initialize \
+  --admin "$ADMIN_ADDRESS" \
   --usdc_address "$USDC_CONTRACT_ID")
 
 # Check if initialization was successful